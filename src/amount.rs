@@ -0,0 +1,428 @@
+//! Exact amounts shared by the Bitcoin and Liquid helpers.
+//!
+//! Amounts are kept in msat so lightning balances, on-chain sats and the BTC
+//! decimal strings used by `bitcoind`/`elementsd` all convert without floats.
+//! `Sat` and `Msat` are the raw counts RPCs take and return; converting
+//! between them and `Amount` is checked. Arithmetic is checked too, and
+//! returns an error instead of panicking on overflow or underflow.
+
+use std::fmt;
+use std::iter::Sum;
+use std::str::FromStr;
+
+pub const MSAT_PER_SAT: u64 = 1_000;
+pub const SAT_PER_BTC: u64 = 100_000_000;
+const BTC_DECIMALS: usize = 8;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AmountError {
+    Overflow,
+    /// A subtraction that would go below zero.
+    Underflow,
+    /// The amount has a msat remainder that cannot be expressed in sats.
+    FractionalSat(u64),
+    Negative(String),
+    TooPrecise(String),
+    Invalid(String),
+}
+
+impl fmt::Display for AmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AmountError::Overflow => write!(f, "amount overflow"),
+            AmountError::Underflow => write!(f, "amount underflow"),
+            AmountError::FractionalSat(msat) => {
+                write!(f, "{} msat is not a whole number of sats", msat)
+            }
+            AmountError::Negative(s) => write!(f, "negative amount: {}", s),
+            AmountError::TooPrecise(s) => write!(
+                f,
+                "more than {} decimals in BTC amount: {}",
+                BTC_DECIMALS, s
+            ),
+            AmountError::Invalid(s) => write!(f, "invalid BTC amount: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for AmountError {}
+
+/// A whole number of sats, as on-chain outputs and `fundchannel` count them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Sat(pub u64);
+
+/// A number of msat, as lightning balances and invoices count them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Msat(pub u64);
+
+impl Sat {
+    pub fn to_msat(self) -> Result<Msat, AmountError> {
+        self.0
+            .checked_mul(MSAT_PER_SAT)
+            .map(Msat)
+            .ok_or(AmountError::Overflow)
+    }
+}
+
+impl Msat {
+    /// Exact conversion; fails if there is a msat remainder.
+    pub fn to_sat(self) -> Result<Sat, AmountError> {
+        if !self.0.is_multiple_of(MSAT_PER_SAT) {
+            return Err(AmountError::FractionalSat(self.0));
+        }
+        Ok(Sat(self.0 / MSAT_PER_SAT))
+    }
+}
+
+impl fmt::Display for Sat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for Msat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount {
+    msat: u64,
+}
+
+impl Amount {
+    pub const ZERO: Amount = Amount { msat: 0 };
+
+    pub const fn from_msat(msat: u64) -> Self {
+        Amount { msat }
+    }
+
+    /// Panics if `sat` does not fit in msat, which no real BTC amount does. For
+    /// constants; values read from transactions or RPCs go through
+    /// `checked_from_sat`.
+    pub const fn from_sat(sat: u64) -> Self {
+        match sat.checked_mul(MSAT_PER_SAT) {
+            Some(msat) => Amount { msat },
+            None => panic!("amount overflow"),
+        }
+    }
+
+    pub fn checked_from_sat(sat: u64) -> Result<Self, AmountError> {
+        Ok(Sat(sat).to_msat()?.into())
+    }
+
+    pub const fn to_msat(self) -> Msat {
+        Msat(self.msat)
+    }
+
+    /// Exact conversion; fails if there is a msat remainder.
+    pub fn to_sat(self) -> Result<Sat, AmountError> {
+        self.to_msat().to_sat()
+    }
+
+    /// Whole sats, dropping any msat remainder.
+    pub const fn to_sat_floor(self) -> u64 {
+        self.msat / MSAT_PER_SAT
+    }
+
+    /// Parses a BTC decimal such as `"0.00012345"` or `"100"`, exactly. Also
+    /// takes the exponent form serde_json prints small JSON numbers in, such
+    /// as `"1e-8"`.
+    pub fn from_btc_str(s: &str) -> Result<Self, AmountError> {
+        let s = s.trim();
+        if let Some((mantissa, exponent)) = s.split_once(['e', 'E']) {
+            let exponent: i32 = exponent
+                .parse()
+                .map_err(|_| AmountError::Invalid(s.to_string()))?;
+            return Amount::from_btc_str(&shift_point(mantissa, exponent)?);
+        }
+        if s.starts_with('-') {
+            return Err(AmountError::Negative(s.to_string()));
+        }
+        let (whole, frac) = s.split_once('.').unwrap_or((s, ""));
+        if whole.is_empty() && frac.is_empty() {
+            return Err(AmountError::Invalid(s.to_string()));
+        }
+        if !whole
+            .chars()
+            .chain(frac.chars())
+            .all(|c| c.is_ascii_digit())
+        {
+            return Err(AmountError::Invalid(s.to_string()));
+        }
+        if frac.len() > BTC_DECIMALS {
+            // Trailing zeros beyond 8 decimals are still exact
+            if frac[BTC_DECIMALS..].chars().any(|c| c != '0') {
+                return Err(AmountError::TooPrecise(s.to_string()));
+            }
+        }
+        let frac = &frac[..frac.len().min(BTC_DECIMALS)];
+        let whole: u64 = if whole.is_empty() {
+            0
+        } else {
+            whole
+                .parse()
+                .map_err(|_| AmountError::Invalid(s.to_string()))?
+        };
+        let frac_sat: u64 = if frac.is_empty() {
+            0
+        } else {
+            format!("{:0<width$}", frac, width = BTC_DECIMALS)
+                .parse()
+                .map_err(|_| AmountError::Invalid(s.to_string()))?
+        };
+        let sat = whole
+            .checked_mul(SAT_PER_BTC)
+            .and_then(|s| s.checked_add(frac_sat))
+            .ok_or(AmountError::Overflow)?;
+        Amount::checked_from_sat(sat)
+    }
+
+    /// Formats as a BTC decimal with 8 places, as `sendtoaddress` expects.
+    pub fn to_btc_string(self) -> Result<String, AmountError> {
+        let sat = self.to_sat()?.0;
+        Ok(format!("{}.{:08}", sat / SAT_PER_BTC, sat % SAT_PER_BTC))
+    }
+
+    pub fn checked_add(self, rhs: Amount) -> Result<Amount, AmountError> {
+        self.msat
+            .checked_add(rhs.msat)
+            .map(Amount::from_msat)
+            .ok_or(AmountError::Overflow)
+    }
+
+    pub fn checked_sub(self, rhs: Amount) -> Result<Amount, AmountError> {
+        self.msat
+            .checked_sub(rhs.msat)
+            .map(Amount::from_msat)
+            .ok_or(AmountError::Underflow)
+    }
+
+    /// `self + rhs`, or the largest amount on overflow.
+    pub fn saturating_add(self, rhs: Amount) -> Amount {
+        Amount::from_msat(self.msat.saturating_add(rhs.msat))
+    }
+
+    /// `self - rhs`, or zero if `rhs` is larger.
+    pub fn saturating_sub(self, rhs: Amount) -> Amount {
+        Amount::from_msat(self.msat.saturating_sub(rhs.msat))
+    }
+
+    /// Signed difference `self - before` in whole sats, for balance deltas.
+    pub fn sat_delta(self, before: Amount) -> i64 {
+        self.to_sat_floor() as i64 - before.to_sat_floor() as i64
+    }
+}
+
+/// Moves the decimal point of `decimal` by `exponent` places to the right.
+fn shift_point(decimal: &str, exponent: i32) -> Result<String, AmountError> {
+    if decimal.starts_with('-') {
+        return Err(AmountError::Negative(decimal.to_string()));
+    }
+    if exponent.unsigned_abs() as usize > 2 * BTC_DECIMALS + 20 {
+        return Err(AmountError::Invalid(format!("{}e{}", decimal, exponent)));
+    }
+    let (whole, frac) = decimal.split_once('.').unwrap_or((decimal, ""));
+    let digits = format!("{}{}", whole, frac);
+    let point = whole.len() as i32 + exponent;
+    Ok(if point <= 0 {
+        format!("0.{}{}", "0".repeat(-point as usize), digits)
+    } else if point as usize >= digits.len() {
+        format!("{}{}", digits, "0".repeat(point as usize - digits.len()))
+    } else {
+        let (whole, frac) = digits.split_at(point as usize);
+        format!("{}.{}", whole, frac)
+    })
+}
+
+/// Sums amounts, failing on overflow.
+impl Sum<Amount> for Result<Amount, AmountError> {
+    fn sum<I: Iterator<Item = Amount>>(mut iter: I) -> Self {
+        iter.try_fold(Amount::ZERO, Amount::checked_add)
+    }
+}
+
+impl From<Msat> for Amount {
+    fn from(msat: Msat) -> Self {
+        Amount::from_msat(msat.0)
+    }
+}
+
+impl TryFrom<Sat> for Amount {
+    type Error = AmountError;
+
+    fn try_from(sat: Sat) -> Result<Self, AmountError> {
+        Amount::checked_from_sat(sat.0)
+    }
+}
+
+impl TryFrom<bitcoincore_rpc::bitcoin::Amount> for Amount {
+    type Error = AmountError;
+
+    fn try_from(amount: bitcoincore_rpc::bitcoin::Amount) -> Result<Self, AmountError> {
+        Amount::checked_from_sat(amount.to_sat())
+    }
+}
+
+impl FromStr for Amount {
+    type Err = AmountError;
+
+    fn from_str(s: &str) -> Result<Self, AmountError> {
+        Amount::from_btc_str(s)
+    }
+}
+
+/// Prints whole sats, with a msat remainder as three decimals when present.
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rem = self.msat % MSAT_PER_SAT;
+        if rem == 0 {
+            write!(f, "{}", self.to_sat_floor())
+        } else {
+            write!(f, "{}.{:03}", self.to_sat_floor(), rem)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_btc_decimals_exactly() {
+        assert_eq!(
+            Amount::from_btc_str("0.00012345"),
+            Ok(Amount::from_sat(12_345))
+        );
+        assert_eq!(
+            Amount::from_btc_str("100"),
+            Ok(Amount::from_sat(100 * SAT_PER_BTC))
+        );
+        assert_eq!(
+            Amount::from_btc_str("21000000.0"),
+            Ok(Amount::from_sat(21_000_000 * SAT_PER_BTC))
+        );
+        assert_eq!(Amount::from_btc_str(".5"), Ok(Amount::from_sat(50_000_000)));
+        assert_eq!(
+            Amount::from_btc_str("0.100000000"),
+            Ok(Amount::from_sat(10_000_000))
+        );
+    }
+
+    #[test]
+    fn parses_exponent_form() {
+        assert_eq!(Amount::from_btc_str("1e-8"), Ok(Amount::from_sat(1)));
+        assert_eq!(Amount::from_btc_str("1.5e-7"), Ok(Amount::from_sat(15)));
+        assert_eq!(
+            Amount::from_btc_str("2E1"),
+            Ok(Amount::from_sat(20 * SAT_PER_BTC))
+        );
+        assert!(matches!(
+            Amount::from_btc_str("1e-9"),
+            Err(AmountError::TooPrecise(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_btc_strings() {
+        assert!(matches!(
+            Amount::from_btc_str("-1"),
+            Err(AmountError::Negative(_))
+        ));
+        assert!(matches!(
+            Amount::from_btc_str("0.123456789"),
+            Err(AmountError::TooPrecise(_))
+        ));
+        assert!(matches!(
+            Amount::from_btc_str("1.2.3"),
+            Err(AmountError::Invalid(_))
+        ));
+        assert!(matches!(
+            Amount::from_btc_str(""),
+            Err(AmountError::Invalid(_))
+        ));
+        assert_eq!(
+            Amount::from_btc_str("999999999999"),
+            Err(AmountError::Overflow)
+        );
+    }
+
+    #[test]
+    fn formats_btc_with_eight_places() {
+        assert_eq!(Amount::from_sat(1).to_btc_string().unwrap(), "0.00000001");
+        assert_eq!(
+            Amount::from_sat(150_000_000).to_btc_string().unwrap(),
+            "1.50000000"
+        );
+        assert_eq!(
+            Amount::from_msat(1_500).to_btc_string(),
+            Err(AmountError::FractionalSat(1_500))
+        );
+    }
+
+    #[test]
+    fn converts_between_sat_and_msat() {
+        assert_eq!(Amount::from_msat(2_000).to_sat(), Ok(Sat(2)));
+        assert_eq!(
+            Amount::from_msat(2_001).to_sat(),
+            Err(AmountError::FractionalSat(2_001))
+        );
+        assert_eq!(Amount::from_msat(2_999).to_sat_floor(), 2);
+        assert_eq!(Sat(3).to_msat(), Ok(Msat(3_000)));
+        assert_eq!(Sat(u64::MAX).to_msat(), Err(AmountError::Overflow));
+        assert_eq!(Amount::try_from(Sat(7)), Ok(Amount::from_msat(7_000)));
+        assert_eq!(Amount::from(Msat(7)), Amount::from_msat(7));
+        assert_eq!(
+            Amount::try_from(bitcoincore_rpc::bitcoin::Amount::from_sat(7)),
+            Ok(Amount::from_msat(7_000))
+        );
+        assert_eq!(
+            Amount::try_from(bitcoincore_rpc::bitcoin::Amount::MAX),
+            Err(AmountError::Overflow)
+        );
+        assert_eq!(
+            Amount::checked_from_sat(u64::MAX),
+            Err(AmountError::Overflow)
+        );
+    }
+
+    #[test]
+    fn arithmetic_is_checked() {
+        let max = Amount::from_msat(u64::MAX);
+        assert_eq!(
+            Amount::from_sat(1).checked_add(Amount::from_sat(2)),
+            Ok(Amount::from_sat(3))
+        );
+        assert_eq!(
+            max.checked_add(Amount::from_msat(1)),
+            Err(AmountError::Overflow)
+        );
+        assert_eq!(
+            Amount::ZERO.checked_sub(Amount::from_msat(1)),
+            Err(AmountError::Underflow)
+        );
+        assert_eq!(
+            Amount::ZERO.saturating_sub(Amount::from_sat(1)),
+            Amount::ZERO
+        );
+        assert_eq!(max.saturating_add(Amount::from_sat(1)), max);
+        assert_eq!(
+            [Amount::from_sat(1), Amount::from_sat(2)]
+                .into_iter()
+                .sum::<Result<Amount, _>>(),
+            Ok(Amount::from_sat(3))
+        );
+        assert_eq!(
+            [max, max].into_iter().sum::<Result<Amount, _>>(),
+            Err(AmountError::Overflow)
+        );
+    }
+
+    #[test]
+    fn displays_sats_with_msat_remainder() {
+        assert_eq!(Amount::from_sat(5).to_string(), "5");
+        assert_eq!(Amount::from_msat(5_042).to_string(), "5.042");
+        assert_eq!(Amount::from_sat(2).sat_delta(Amount::from_sat(5)), -3);
+    }
+}
@@ -0,0 +1,356 @@
+//! Many swaps at once, over several channels and peers, in both directions and
+//! both assets, to surface races such as UTXO contention on opening
+//! transactions and overlapping mining.
+//!
+//! PeerSwap allows one active swap per channel, so swaps run in rounds with at
+//! most one per channel, and the concurrency comes from the channels.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+use super::bitcoin_rpc::AsyncBitcoinClient as BitcoinClient;
+use super::amount::{self, Amount};
+use super::ids::Scid;
+use super::discovery::channel_balances;
+use super::premium::{onchain_balance, run_swap, Direction, ASSETS};
+use super::recorder;
+use super::setup;
+use super::setup_report::SetupReport;
+use super::{cli, get_channel_balance, liquid_generate};
+
+const TERMINAL_STATES: [&str; 4] = ["State_ClaimedPreimage", "State_ClaimedCoop", "State_ClaimedCsv", "State_SwapCanceled"];
+/// What a claim transaction may cost on top of the recorded opening fee.
+const MAX_CLAIM_FEE: Amount = Amount::from_sat(5_000);
+
+/// Who mines blocks while the swaps run.
+#[derive(Clone, Copy, Debug)]
+pub enum MiningMode {
+    /// One loop mines for every swap.
+    Central,
+    /// Every swap runs through the swap helpers, each mining on its own.
+    PerSwap,
+}
+
+#[derive(Clone, Debug)]
+pub struct SwapSpec {
+    pub initiator: String,
+    /// The node on the other end of the channel.
+    pub peer: String,
+    pub scid: Scid,
+    pub asset: &'static str,
+    pub direction: Direction,
+    pub amount: Amount,
+}
+
+#[derive(Clone, Debug)]
+pub struct SwapOutcome {
+    pub spec: SwapSpec,
+    pub swap_id: Option<String>,
+    pub state: String,
+    pub premium: i64,
+    pub onchain_fee: Amount,
+    pub error: Option<String>,
+}
+
+impl SwapOutcome {
+    fn failed(spec: SwapSpec, error: String) -> Self {
+        SwapOutcome { spec, swap_id: None, state: String::new(), premium: 0, onchain_fee: Amount::ZERO, error: Some(error) }
+    }
+
+    pub fn completed(&self) -> bool {
+        self.state == "State_ClaimedPreimage" || self.state == "State_ClaimedCoop"
+    }
+}
+
+/// Funds `container` on-chain with BTC and L-BTC, unless it holds enough already.
+pub async fn fund_node(btc: &BitcoinClient, report: &mut SetupReport, container: &str) -> Result<()> {
+    setup::ensure_btc(btc, report, container, Amount::from_sat(amount::SAT_PER_BTC)).await?;
+    setup::ensure_lbtc(report, container, Amount::from_sat(amount::SAT_PER_BTC)).await
+}
+
+/// Makes sure `count` channels of `capacity` exist for every `(from, to, count)`,
+/// and that `to` holds at least a quarter of `capacity` on each so swaps can
+/// run both ways.
+/// Returns `(from, to, scid)` for every channel.
+pub async fn open_channels(
+    btc: &BitcoinClient,
+    report: &mut SetupReport,
+    pairs: &[(&str, &str, usize)],
+    capacity: Amount,
+    mine_to: &bitcoincore_rpc::bitcoin::Address,
+) -> Result<Vec<(String, String, Scid)>> {
+    let mut channels = Vec::new();
+    for &(from, to, count) in pairs {
+        for scid in setup::ensure_channels(btc, report, from, to, count, capacity, mine_to).await? {
+            setup::ensure_channel_balance(report, from, to, scid, Amount::from_msat(capacity.to_msat().0 / 4)).await?;
+            channels.push((from.to_string(), to.to_string(), scid));
+        }
+    }
+    Ok(channels)
+}
+
+/// One swap per channel, asset and direction.
+pub fn full_matrix(channels: &[(String, String, Scid)], amount: Amount) -> Vec<SwapSpec> {
+    let mut specs = Vec::new();
+    for (initiator, peer, scid) in channels {
+        for asset in ASSETS {
+            for direction in Direction::ALL {
+                specs.push(SwapSpec { initiator: initiator.clone(), peer: peer.clone(), scid: *scid, asset, direction, amount });
+            }
+        }
+    }
+    specs
+}
+
+/// Splits `specs` into rounds with at most one swap per channel, keeping the
+/// order of each channel's swaps.
+fn rounds(specs: &[SwapSpec]) -> Vec<Vec<SwapSpec>> {
+    let mut rounds: Vec<Vec<SwapSpec>> = Vec::new();
+    let mut per_channel: HashMap<Scid, usize> = HashMap::new();
+    for spec in specs {
+        let round = per_channel.entry(spec.scid).or_default();
+        if rounds.len() <= *round {
+            rounds.push(Vec::new());
+        }
+        rounds[*round].push(spec.clone());
+        *round += 1;
+    }
+    rounds
+}
+
+/// Waits for a spawned swap task, turning a panic or cancellation into an error.
+async fn join<T>(handle: tokio::task::JoinHandle<Result<T>>) -> Result<T> {
+    handle.await.context("Swap task failed")?
+}
+
+async fn start_swap(spec: &SwapSpec) -> Result<String> {
+    let cmd = match spec.direction {
+        Direction::SwapOut => "peerswap-swap-out",
+        Direction::SwapIn => "peerswap-swap-in",
+    };
+    let swap = cli(&spec.initiator, &[cmd, &spec.scid.to_string(), &spec.amount.to_sat()?.to_string(), spec.asset, "10000"]).await?;
+    Ok(swap["id"].as_str().context("No swap id")?.to_string())
+}
+
+async fn run_central(btc: &BitcoinClient, specs: &[SwapSpec], mine_to: &bitcoincore_rpc::bitcoin::Address) -> Result<Vec<SwapOutcome>> {
+    let handles: Vec<_> = specs
+        .iter()
+        .cloned()
+        .map(|spec| tokio::spawn(async move { start_swap(&spec).await }))
+        .collect();
+    let mut started = Vec::new();
+    for handle in handles {
+        started.push(join(handle).await);
+    }
+
+    let mut outcomes: Vec<SwapOutcome> = specs
+        .iter()
+        .cloned()
+        .zip(started)
+        .map(|(spec, id)| match id {
+            Ok(id) => SwapOutcome { spec, swap_id: Some(id), state: String::new(), premium: 0, onchain_fee: Amount::ZERO, error: None },
+            Err(e) => SwapOutcome::failed(spec, e.to_string()),
+        })
+        .collect();
+
+    for _ in 0..60 {
+        btc.generate_to_address(1, mine_to).await?;
+        liquid_generate(1).await?;
+        recorder::sleep(std::time::Duration::from_secs(2)).await;
+        let mut pending = 0;
+        for outcome in outcomes.iter_mut().filter(|o| o.error.is_none() && !TERMINAL_STATES.contains(&o.state.as_str())) {
+            let id = outcome.swap_id.as_deref().unwrap_or_default();
+            let status = cli(&outcome.spec.initiator, &["peerswap-getswap", id]).await?;
+            outcome.state = status["current"].as_str().unwrap_or("").to_string();
+            tracing::debug!(node = %outcome.spec.initiator, scid = %outcome.spec.scid, swap_id = id, state = %outcome.state, "Polled swap");
+            let agreement = match outcome.spec.direction {
+                Direction::SwapOut => "swap_out_agreement",
+                Direction::SwapIn => "swap_in_agreement",
+            };
+            outcome.premium = status["data"][agreement]["premium"].as_i64().unwrap_or(0);
+            outcome.onchain_fee = Amount::from_sat(status["data"]["opening_tx_fee"].as_u64().unwrap_or(0));
+            if !TERMINAL_STATES.contains(&outcome.state.as_str()) {
+                pending += 1;
+            }
+        }
+        if pending == 0 {
+            break;
+        }
+    }
+    Ok(outcomes)
+}
+
+async fn run_per_swap(btc: &BitcoinClient, specs: &[SwapSpec], mine_to: &bitcoincore_rpc::bitcoin::Address) -> Vec<SwapOutcome> {
+    let handles: Vec<_> = specs
+        .iter()
+        .cloned()
+        .map(|spec| {
+            let (btc, mine_to) = (btc.clone(), mine_to.clone());
+            tokio::spawn(async move {
+                run_swap(&btc, &spec.initiator, spec.scid, spec.asset, spec.direction, spec.amount, 10_000, &mine_to).await
+            })
+        })
+        .collect();
+    let mut outcomes = Vec::new();
+    for (spec, handle) in specs.iter().cloned().zip(handles) {
+        outcomes.push(match join(handle).await {
+            Ok(result) => SwapOutcome {
+                spec,
+                swap_id: Some(result.swap_id),
+                state: result.state,
+                premium: result.premium,
+                onchain_fee: result.onchain_fee,
+                error: None,
+            },
+            Err(e) => SwapOutcome::failed(spec, e.to_string()),
+        });
+    }
+    outcomes
+}
+
+/// Channel and on-chain balances of every node taking part in a set of swaps.
+struct Balances {
+    channels: HashMap<(String, Scid), Amount>,
+    onchain: HashMap<(String, &'static str), Amount>,
+}
+
+impl Balances {
+    async fn read(specs: &[SwapSpec]) -> Result<Self> {
+        let mut balances = Balances { channels: HashMap::new(), onchain: HashMap::new() };
+        for spec in specs {
+            for node in [&spec.initiator, &spec.peer] {
+                if let std::collections::hash_map::Entry::Vacant(e) = balances.channels.entry((node.clone(), spec.scid)) {
+                    e.insert(get_channel_balance(node, spec.scid).await?);
+                }
+                for asset in ASSETS {
+                    if let std::collections::hash_map::Entry::Vacant(e) = balances.onchain.entry((node.clone(), asset)) {
+                        e.insert(onchain_balance(node, asset).await?);
+                    }
+                }
+            }
+        }
+        Ok(balances)
+    }
+}
+
+/// Checks that both ends of every channel hold the largest swap planned on it
+/// plus the channel reserve, so the first round can run in either direction.
+async fn check_liquidity(specs: &[SwapSpec]) -> Result<()> {
+    let mut largest: HashMap<(String, Scid), Amount> = HashMap::new();
+    for spec in specs {
+        for node in [&spec.initiator, &spec.peer] {
+            let entry = largest.entry((node.clone(), spec.scid)).or_default();
+            *entry = (*entry).max(spec.amount);
+        }
+    }
+    let mut problems = Vec::new();
+    for ((node, scid), amount) in &largest {
+        let channel = channel_balances(node).await?
+            .into_iter()
+            .find(|c| c.scid == *scid)
+            .with_context(|| format!("{} has no active channel {}", node, scid))?;
+        let needed = amount.checked_add(channel.reserve())?;
+        if channel.local < needed {
+            problems.push(format!("{} holds {} on {}, needs {}", node, channel.local, scid, needed));
+        }
+    }
+    anyhow::ensure!(problems.is_empty(), "Channels cannot carry the swaps:\n  {}", problems.join("\n  "));
+    Ok(())
+}
+
+/// Sats the initiator of a completed swap `o` gains in its channel. Its
+/// on-chain wallet moves the other way, and the peer mirrors both.
+fn initiator_channel_delta(o: &SwapOutcome) -> i64 {
+    let amount = o.spec.amount.to_sat_floor() as i64;
+    match o.spec.direction {
+        Direction::SwapOut => -amount,
+        Direction::SwapIn => amount,
+    }
+}
+
+/// Checks that no swap was left mid-flight, and that the channel balance on
+/// both ends and the on-chain BTC and L-BTC wallets of every node moved by
+/// what the completed swaps account for, give or take premiums and fees.
+async fn reconcile(outcomes: &[SwapOutcome], before: &Balances) -> Result<()> {
+    let mut problems = Vec::new();
+    for o in outcomes {
+        if let Some(e) = &o.error {
+            problems.push(format!("{} {} {} on {}: {}", o.spec.initiator, o.spec.direction.operation(), o.spec.asset, o.spec.scid, e));
+        } else if !TERMINAL_STATES.contains(&o.state.as_str()) {
+            problems.push(format!("swap {:?} stuck in {}", o.swap_id, o.state));
+        }
+    }
+
+    for ((node, scid), local_before) in &before.channels {
+        let channel = channel_balances(node).await?
+            .into_iter()
+            .find(|c| c.scid == *scid)
+            .with_context(|| format!("Channel {} disappeared", scid))?;
+        let (mut expected, mut slack) = (0i64, 0i64);
+        for o in outcomes.iter().filter(|o| o.completed() && o.spec.scid == *scid) {
+            // The peer's side moves the other way
+            expected += if o.spec.initiator == *node { initiator_channel_delta(o) } else { -initiator_channel_delta(o) };
+            slack += o.premium.abs() + o.onchain_fee.to_sat_floor() as i64 + 1;
+        }
+        let actual = channel.local.sat_delta(*local_before);
+        println!("{} {}: delta={} expected={}±{}", node, scid, actual, expected, slack);
+        if (actual - expected).abs() > slack {
+            problems.push(format!("{} on {} moved {} sats, expected {}±{}", node, scid, actual, expected, slack));
+        }
+    }
+
+    for ((node, asset), onchain_before) in &before.onchain {
+        let (mut expected, mut slack) = (0i64, 0i64);
+        for o in outcomes.iter().filter(|o| o.completed() && o.spec.asset == *asset) {
+            let initiator_delta = -initiator_channel_delta(o);
+            expected += match node {
+                n if *n == o.spec.initiator => initiator_delta,
+                n if *n == o.spec.peer => -initiator_delta,
+                _ => continue,
+            };
+            slack += o.premium.abs() + o.onchain_fee.checked_add(MAX_CLAIM_FEE)?.to_sat_floor() as i64;
+        }
+        let actual = onchain_balance(node, asset).await?.sat_delta(*onchain_before);
+        println!("{} {} on-chain: delta={} expected={}±{}", node, asset, actual, expected, slack);
+        if (actual - expected).abs() > slack {
+            problems.push(format!("{} {} wallet moved {} sats, expected {}±{}", node, asset, actual, expected, slack));
+        }
+    }
+
+    anyhow::ensure!(problems.is_empty(), "Concurrent swaps failed:\n  {}", problems.join("\n  "));
+    Ok(())
+}
+
+/// Runs `specs` concurrently and reconciles the results. `mine_to` must not
+/// belong to any of the nodes, or coinbases would skew their on-chain wallets.
+pub async fn run(btc: &BitcoinClient, specs: &[SwapSpec], mode: MiningMode, mine_to: &bitcoincore_rpc::bitcoin::Address) -> Result<Vec<SwapOutcome>> {
+    check_liquidity(specs).await?;
+    let before = Balances::read(specs).await?;
+
+    let rounds = rounds(specs);
+    println!("Running {} swaps in {} rounds of one per channel ({:?} mining)", specs.len(), rounds.len(), mode);
+    let mut outcomes = Vec::new();
+    for round in &rounds {
+        outcomes.extend(match mode {
+            MiningMode::Central => run_central(btc, round, mine_to).await?,
+            MiningMode::PerSwap => run_per_swap(btc, round, mine_to).await,
+        });
+    }
+    for o in &outcomes {
+        println!(
+            "  {} {} {} on {}: {} premium={} onchain_fee={}{}",
+            o.spec.initiator,
+            o.spec.direction.operation(),
+            o.spec.asset,
+            o.spec.scid,
+            o.state,
+            o.premium,
+            o.onchain_fee,
+            o.error.as_deref().map(|e| format!(" error={}", e)).unwrap_or_default()
+        );
+    }
+    // Let in-flight HTLCs settle before reading balances
+    recorder::sleep(std::time::Duration::from_secs(5)).await;
+    reconcile(&outcomes, &before).await?;
+    Ok(outcomes)
+}
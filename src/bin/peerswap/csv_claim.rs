@@ -0,0 +1,273 @@
+//! Swaps where the peer node goes away mid-swap, leaving the initiator to
+//! recover its opening transaction through the CSV claim path.
+
+use anyhow::{Context, Result};
+use tokio::process::Command;
+
+use super::bitcoin_rpc::AsyncBitcoinClient as BitcoinClient;
+use super::amount::Amount;
+use super::error::{self, Error};
+use super::ids::{PeerAddress, Scid};
+use super::premium::onchain_balance;
+use super::process_env;
+use super::{recorder, telemetry};
+use super::regtest_env::container;
+use super::setup::peer_address;
+use super::{cli, elements_cli, elements_tx, get_channel_balance, neutral_address, SWAP_AMOUNT};
+
+/// CSV delays PeerSwap puts on the opening transaction's refund path.
+pub const BTC_CSV: u64 = 1008;
+pub const LBTC_CSV: u64 = 60;
+
+// Upper bound for the opening plus claim transaction fees on regtest.
+const MAX_FEES: Amount = Amount::from_sat(20_000);
+
+/// How the peer node is taken offline.
+#[derive(Clone, Copy, Debug)]
+pub enum Outage {
+    Stop,
+    Pause,
+}
+
+/// Point in the swap at which the peer is taken offline.
+#[derive(Clone, Copy, Debug)]
+pub enum OutagePoint {
+    /// As soon as the initiator has broadcast the opening transaction.
+    OpeningBroadcast,
+    /// After mining this many blocks on top of the opening transaction; must stay
+    /// below the confirmations the peer waits for before paying the claim invoice.
+    OpeningConfirmations(u64),
+}
+
+#[tracing::instrument(name = "rpc", skip_all, fields(node = "docker", method = args.first().copied().unwrap_or_default()))]
+async fn docker(args: &[&str]) -> error::Result<()> {
+    let subcommand = args.first().copied().unwrap_or_default();
+    telemetry::rpc(recorder::call("docker", subcommand, args, async {
+        let output = Command::new("docker").args(args).output().await?;
+        if !output.status.success() {
+            return Err(Error::Command {
+                command: format!("docker {}", subcommand),
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }))
+    .await
+    .map_err(Error::classify)
+}
+
+impl Outage {
+    async fn begin(self, service: &str) -> Result<()> {
+        if let Some(local) = process_env::active() {
+            return match self {
+                Outage::Stop => local.stop(service).await,
+                Outage::Pause => local.signal(service, "STOP").await,
+            };
+        }
+        match self {
+            Outage::Stop => docker(&["stop", &container(service)]).await?,
+            Outage::Pause => docker(&["pause", &container(service)]).await?,
+        }
+        Ok(())
+    }
+
+    async fn end(self, service: &str) -> Result<()> {
+        if let Some(local) = process_env::active() {
+            return match self {
+                Outage::Stop => local.restart(service).await,
+                Outage::Pause => local.signal(service, "CONT").await,
+            };
+        }
+        match self {
+            Outage::Stop => docker(&["start", &container(service)]).await?,
+            Outage::Pause => docker(&["unpause", &container(service)]).await?,
+        }
+        Ok(())
+    }
+}
+
+fn csv_blocks(asset: &str) -> u64 {
+    if asset == "lbtc" { LBTC_CSV } else { BTC_CSV }
+}
+
+// Mines to an address no node watches, so that the initiator's on-chain
+// balance only moves by what the swap did to it.
+async fn mine_blocks(btc: &BitcoinClient, asset: &str, blocks: u64) -> Result<()> {
+    match asset {
+        "btc" => {
+            btc.generate_to_address(blocks, &neutral_address()).await?;
+        }
+        _ => super::liquid_generate(blocks).await?,
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip(container), fields(node = container))]
+pub async fn wait_for_opening_tx(container: &str, swap_id: &str) -> Result<String> {
+    for _ in 0..60 {
+        let status = cli(container, &["peerswap-getswap", swap_id]).await?;
+        if let Some(hex) = status["data"]["opening_tx_hex"].as_str()
+            && !hex.is_empty()
+        {
+            return Ok(hex.to_string());
+        }
+        recorder::sleep(std::time::Duration::from_secs(1)).await;
+    }
+    Err(Error::Timeout(format!("waiting for opening transaction of swap {}", swap_id)).into())
+}
+
+fn opening_txid(asset: &str, tx_hex: &str) -> Result<String> {
+    match asset {
+        "btc" => {
+            let tx: bitcoincore_rpc::bitcoin::Transaction =
+                bitcoincore_rpc::bitcoin::consensus::deserialize(&hex::decode(tx_hex)?)?;
+            Ok(tx.compute_txid().to_string())
+        }
+        _ => Ok(elements_tx::decode(tx_hex)?.txid().to_string()),
+    }
+}
+
+/// Outpoints spent by `txid`, searched for in the last `depth` blocks.
+async fn claim_inputs(btc: &BitcoinClient, asset: &str, txid: &str, depth: u64) -> Result<Vec<(String, u32)>> {
+    match asset {
+        "btc" => {
+            let tip = btc.get_block_count().await?;
+            for height in (tip.saturating_sub(depth)..=tip).rev() {
+                let block = btc.get_block(&btc.get_block_hash(height).await?).await?;
+                if let Some(tx) = block.txdata.iter().find(|tx| tx.compute_txid().to_string() == txid) {
+                    return Ok(tx
+                        .input
+                        .iter()
+                        .map(|i| (i.previous_output.txid.to_string(), i.previous_output.vout))
+                        .collect());
+                }
+            }
+        }
+        _ => {
+            let tip = elements_cli(&["getblockcount"]).await?.as_u64().context("No block count")?;
+            for height in (tip.saturating_sub(depth)..=tip).rev() {
+                let hash = elements_cli(&["getblockhash", &height.to_string()]).await?;
+                let block = elements_cli(&["getblock", hash.as_str().context("No block hash")?, "2"]).await?;
+                if let Some(tx) = block["tx"]
+                    .as_array()
+                    .and_then(|txs| txs.iter().find(|tx| tx["txid"].as_str() == Some(txid)))
+                {
+                    return tx["vin"]
+                        .as_array()
+                        .context("No vin")?
+                        .iter()
+                        .map(|i| {
+                            Ok((
+                                i["txid"].as_str().context("No input txid")?.to_string(),
+                                i["vout"].as_u64().context("No input vout")? as u32,
+                            ))
+                        })
+                        .collect();
+                }
+            }
+        }
+    }
+    anyhow::bail!("Claim transaction {} not found in the last {} blocks", txid, depth)
+}
+
+/// Runs a swap-in from `initiator`, takes `peer` offline at `point`, mines past
+/// the CSV expiry and checks that the initiator claims its funds back.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip_all, fields(node = initiator, scid, asset, swap_id = tracing::field::Empty))]
+pub async fn run_csv_claim(
+    btc: &BitcoinClient,
+    initiator: &str,
+    peer: &str,
+    peer_addr: &PeerAddress,
+    scid: Scid,
+    asset: &str,
+    outage: Outage,
+    point: OutagePoint,
+) -> Result<()> {
+    let onchain_before = onchain_balance(initiator, asset).await?;
+    let channel_before = get_channel_balance(initiator, scid).await?;
+
+    let swap = cli(initiator, &["peerswap-swap-in", &scid.to_string(), &SWAP_AMOUNT.to_sat()?.to_string(), asset, "10000"]).await?;
+    let swap_id = swap["id"].as_str().context("No swap id")?.to_string();
+    tracing::Span::current().record("swap_id", &swap_id);
+
+    let opening_tx_hex = wait_for_opening_tx(initiator, &swap_id).await?;
+    if let OutagePoint::OpeningConfirmations(blocks) = point {
+        mine_blocks(btc, asset, blocks).await?;
+    }
+    outage.begin(peer).await?;
+    println!("{} {:?} at {:?} of swap {}", peer, outage, point, swap_id);
+
+    let result: Result<()> = async {
+        mine_blocks(btc, asset, csv_blocks(asset)).await?;
+        let mut claimed = None;
+        for _ in 0..30 {
+            mine_blocks(btc, asset, 1).await?;
+            recorder::sleep(std::time::Duration::from_secs(2)).await;
+            let status = cli(initiator, &["peerswap-getswap", &swap_id]).await?;
+            let state = status["current"].as_str().unwrap_or("");
+            tracing::debug!(state, "Polled swap");
+            anyhow::ensure!(
+                state != "State_ClaimedPreimage" && state != "State_ClaimedCoop",
+                "Swap claimed by {} while it was offline: {}", peer, state
+            );
+            if state == "State_ClaimedCsv" {
+                claimed = Some(status);
+                break;
+            }
+        }
+        let status = claimed.context("Timeout waiting for State_ClaimedCsv")?;
+
+        let claim_txid = status["data"]["claim_txid"].as_str().context("No claim_txid")?;
+        let opening_txid = opening_txid(asset, &opening_tx_hex)?;
+        let inputs = claim_inputs(btc, asset, claim_txid, 40).await?;
+        anyhow::ensure!(
+            inputs.iter().any(|(txid, _)| *txid == opening_txid),
+            "Claim transaction {} does not spend opening transaction {}", claim_txid, opening_txid
+        );
+
+        let onchain_after = onchain_balance(initiator, asset).await?;
+        println!("{} CSV claim {}: on-chain before={} after={}", asset, claim_txid, onchain_before, onchain_after);
+        // The swap amount came back, so all the initiator is out of is the
+        // opening and claim fees
+        let fees = onchain_before
+            .checked_sub(onchain_after)
+            .with_context(|| format!("{} gained on-chain funds on a CSV claim: {} -> {}", initiator, onchain_before, onchain_after))?;
+        anyhow::ensure!(
+            fees > Amount::ZERO && fees <= MAX_FEES,
+            "{} paid {} in fees on a CSV claim, expected between 0 and {}", initiator, fees, MAX_FEES
+        );
+        anyhow::ensure!(
+            get_channel_balance(initiator, scid).await? == channel_before,
+            "Channel balance moved on a CSV-claimed swap"
+        );
+        Ok(())
+    }
+    .await;
+
+    // Always bring the peer back, so later scenarios have a channel to work with
+    outage.end(peer).await?;
+    recorder::sleep(std::time::Duration::from_secs(5)).await;
+    let _ = cli(initiator, &["connect", &peer_addr.to_string()]).await;
+    result
+}
+
+/// Stops and pauses the peer at different points of a swap, for both assets.
+pub async fn run_all(
+    btc: &BitcoinClient,
+    initiator: &str,
+    peer: &str,
+    scid: Scid,
+) -> Result<()> {
+    let peer_addr = peer_address(peer).await?;
+    for asset in ["btc", "lbtc"] {
+        for (outage, point) in [
+            (Outage::Stop, OutagePoint::OpeningBroadcast),
+            (Outage::Pause, OutagePoint::OpeningConfirmations(1)),
+        ] {
+            run_csv_claim(btc, initiator, peer, &peer_addr, scid, asset, outage, point).await?;
+        }
+    }
+    Ok(())
+}
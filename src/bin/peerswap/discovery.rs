@@ -0,0 +1,183 @@
+//! Discovery of PeerSwap-capable peers and automatic channel selection, so a
+//! swap can be asked for by amount rather than by scid.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+use super::bitcoin_rpc::AsyncBitcoinClient as BitcoinClient;
+use super::amount::Amount;
+use super::ids::{NodeId, Scid};
+use super::premium::{run_swap, Direction, ASSETS};
+use super::{cli, SwapResult};
+
+/// Premium rate in ppm keyed by asset and operation (`swap_in`/`swap_out`).
+pub type PremiumRates = HashMap<(&'static str, &'static str), u64>;
+
+#[derive(Clone, Debug)]
+pub struct PeerSwapPeer {
+    pub node_id: NodeId,
+    pub swaps_allowed: bool,
+    pub supported_assets: Vec<&'static str>,
+    pub premiums: PremiumRates,
+}
+
+impl PeerSwapPeer {
+    pub fn premium_ppm(&self, asset: &str, direction: Direction) -> Option<u64> {
+        self.premiums
+            .iter()
+            .find(|((a, op), _)| *a == asset && *op == direction.operation())
+            .map(|(_, ppm)| *ppm)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChannelBalance {
+    pub scid: Scid,
+    pub peer_id: NodeId,
+    pub local: Amount,
+    pub capacity: Amount,
+}
+
+impl ChannelBalance {
+    pub fn remote(&self) -> Amount {
+        self.capacity.checked_sub(self.local).unwrap_or_default()
+    }
+
+    /// What each side must keep in the channel: 1% of its capacity.
+    pub fn reserve(&self) -> Amount {
+        Amount::from_msat(self.capacity.to_msat().0 / 100)
+    }
+
+    pub fn ratio_ppm(&self) -> u64 {
+        if self.capacity == Amount::ZERO {
+            return 0;
+        }
+        (self.local.to_msat().0 as u128 * 1_000_000 / self.capacity.to_msat().0 as u128) as u64
+    }
+}
+
+/// A channel picked for a swap.
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub channel: ChannelBalance,
+    pub asset: &'static str,
+    pub premium_ppm: u64,
+}
+
+/// Peers the PeerSwap plugin in `container` knows to run PeerSwap.
+pub async fn list_peers(container: &str) -> Result<Vec<PeerSwapPeer>> {
+    let peers = cli(container, &["peerswap-listpeers"]).await?;
+    Ok(peers
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|peer| {
+            let node_id = peer["nodeid"].as_str()?.parse().ok()?;
+            let advertised: Vec<&str> = peer["supported_assets"]
+                .as_array()
+                .map(|a| a.iter().filter_map(|v| v.as_str()).collect())
+                .unwrap_or_default();
+            let supported_assets: Vec<&'static str> = ASSETS.into_iter().filter(|a| advertised.contains(a)).collect();
+            let mut premiums = PremiumRates::new();
+            for &asset in &supported_assets {
+                for direction in Direction::ALL {
+                    let key = format!("{}_{}_premium_rate_ppm", asset, direction.operation());
+                    if let Some(ppm) = peer[key.as_str()].as_u64() {
+                        premiums.insert((asset, direction.operation()), ppm);
+                    }
+                }
+            }
+            Some(PeerSwapPeer {
+                node_id,
+                swaps_allowed: peer["swaps_allowed"].as_bool().unwrap_or(true),
+                supported_assets,
+                premiums,
+            })
+        })
+        .collect())
+}
+
+/// Active channels of the node in `container`, as `get_channel_balance` reads them.
+pub async fn channel_balances(container: &str) -> Result<Vec<ChannelBalance>> {
+    let funds = cli(container, &["listfunds"]).await?;
+    Ok(funds["channels"]
+        .as_array()
+        .context("No channels")?
+        .iter()
+        .filter(|c| c["state"].as_str() == Some("CHANNELD_NORMAL"))
+        .filter_map(|c| {
+            Some(ChannelBalance {
+                scid: c["short_channel_id"].as_str()?.parse().ok()?,
+                peer_id: c["peer_id"].as_str()?.parse().ok()?,
+                local: Amount::from_msat(c["our_amount_msat"].as_u64()?),
+                capacity: Amount::from_msat(c["amount_msat"].as_u64()?),
+            })
+        })
+        .collect())
+}
+
+/// Picks the channel to run a swap of `amount` over: the side that pays must
+/// hold the amount plus a 1% reserve, then the lowest premium wins, then the
+/// channel left closest to balanced, then the largest capacity.
+pub async fn choose_channel(container: &str, direction: Direction, amount: Amount, asset: Option<&str>) -> Result<Candidate> {
+    let peers: HashMap<NodeId, PeerSwapPeer> = list_peers(container).await?
+        .into_iter()
+        .filter(|p| p.swaps_allowed)
+        .map(|p| (p.node_id, p))
+        .collect();
+
+    let mut candidates = Vec::new();
+    for channel in channel_balances(container).await? {
+        let Some(peer) = peers.get(&channel.peer_id) else { continue };
+        let reserve = channel.reserve();
+        let paying_side = match direction {
+            Direction::SwapOut => channel.local,
+            Direction::SwapIn => channel.remote(),
+        };
+        if paying_side < amount.checked_add(reserve)? {
+            continue;
+        }
+        for &a in peer.supported_assets.iter().filter(|a| asset.is_none_or(|want| want == **a)) {
+            let Some(premium_ppm) = peer.premium_ppm(a, direction) else { continue };
+            candidates.push(Candidate { channel: channel.clone(), asset: a, premium_ppm });
+        }
+    }
+
+    candidates
+        .into_iter()
+        .min_by_key(|c| {
+            // The paying side holds at least `amount`, so neither end is clamped
+            let local_after = match direction {
+                Direction::SwapOut => c.channel.local.saturating_sub(amount),
+                Direction::SwapIn => c.channel.local.saturating_add(amount),
+            };
+            let half = Amount::from_msat(c.channel.capacity.to_msat().0 / 2);
+            let imbalance = local_after.max(half).saturating_sub(local_after.min(half));
+            (c.premium_ppm, imbalance, std::cmp::Reverse(c.channel.capacity))
+        })
+        .with_context(|| format!("No PeerSwap channel can {} {} sats", direction.operation(), amount))
+}
+
+/// Swaps `amount` in `direction` over whichever channel `choose_channel` picks.
+pub async fn swap_any(
+    btc: &BitcoinClient,
+    container: &str,
+    direction: Direction,
+    amount: Amount,
+    asset: Option<&str>,
+    max_premium_ppm: u64,
+    mine_to: &bitcoincore_rpc::bitcoin::Address,
+) -> Result<(Candidate, SwapResult)> {
+    let candidate = choose_channel(container, direction, amount, asset).await?;
+    println!(
+        "Chose {} with {} for {} {} (premium {}ppm)",
+        candidate.channel.scid,
+        candidate.channel.peer_id,
+        direction.operation(),
+        candidate.asset,
+        candidate.premium_ppm
+    );
+    let result = run_swap(btc, container, candidate.channel.scid, candidate.asset, direction, amount, max_premium_ppm, mine_to).await?;
+    Ok((candidate, result))
+}
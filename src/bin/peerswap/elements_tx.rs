@@ -0,0 +1,95 @@
+//! Native decoding of Elements transactions: explicit fee outputs are read
+//! directly and confidential L-BTC outputs are unblinded with keys from the
+//! swap record or the `elementsd` wallet.
+
+use anyhow::{Context, Result};
+use elements::hashes::{hmac, sha256, Hash, HashEngine};
+use elements::secp256k1_zkp::{Secp256k1, SecretKey, Verification};
+use elements::{AssetId, Script, Transaction, TxOut};
+use std::str::FromStr;
+
+use super::amount::Amount;
+use super::{elements_cli, elements_cli_raw};
+
+/// The `elementsd` wallet PeerSwap is configured with in `peerswap.conf`.
+pub const LIQUID_WALLET: &str = "peerswap";
+
+pub fn decode(tx_hex: &str) -> Result<Transaction> {
+    Ok(elements::encode::deserialize(&hex::decode(tx_hex)?)?)
+}
+
+/// The chain's policy asset, which is not the mainnet L-BTC id on liquidregtest.
+pub async fn policy_asset() -> Result<AssetId> {
+    let labels = elements_cli(&["dumpassetlabels"]).await?;
+    let asset = labels["bitcoin"].as_str().context("No bitcoin asset label")?;
+    Ok(AssetId::from_str(asset)?)
+}
+
+/// Keys that may unblind outputs of a transaction.
+#[derive(Default)]
+pub struct BlindingKeys {
+    /// SLIP-77 master key of a wallet; per-script keys are derived from it.
+    master: Option<[u8; 32]>,
+    keys: Vec<SecretKey>,
+}
+
+impl BlindingKeys {
+    pub async fn from_wallet(wallet: &str) -> Result<Self> {
+        let master = elements_cli_raw(&[&format!("-rpcwallet={}", wallet), "dumpmasterblindingkey"]).await?;
+        let master: [u8; 32] = hex::decode(master.trim_matches('"'))?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("master blinding key is not 32 bytes"))?;
+        Ok(BlindingKeys { master: Some(master), keys: Vec::new() })
+    }
+
+    /// Adds a blinding private key given as hex, such as a swap's `blinding_key`.
+    pub fn with_key(mut self, key_hex: &str) -> Result<Self> {
+        self.keys.push(SecretKey::from_slice(&hex::decode(key_hex)?)?);
+        Ok(self)
+    }
+
+    fn candidates(&self, script: &Script) -> Vec<SecretKey> {
+        let mut keys = self.keys.clone();
+        if let Some(master) = self.master {
+            // Elements Core derives HMAC-SHA256(master, scriptPubKey)
+            let mut engine = hmac::HmacEngine::<sha256::Hash>::new(&master);
+            engine.input(script.as_bytes());
+            let derived = hmac::Hmac::<sha256::Hash>::from_engine(engine);
+            if let Ok(key) = SecretKey::from_slice(derived.as_byte_array()) {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+}
+
+/// Value of `txout` in `policy_asset`, unblinding it if it is confidential.
+pub fn output_amount<C: Verification>(
+    secp: &Secp256k1<C>,
+    txout: &TxOut,
+    keys: &BlindingKeys,
+    policy_asset: AssetId,
+) -> Result<Amount> {
+    if let (Some(value), Some(asset)) = (txout.value.explicit(), txout.asset.explicit()) {
+        anyhow::ensure!(asset == policy_asset, "Output is in asset {}, not L-BTC", asset);
+        return Ok(Amount::checked_from_sat(value)?);
+    }
+    for key in keys.candidates(&txout.script_pubkey) {
+        if let Ok(secrets) = txout.unblind(secp, key) {
+            anyhow::ensure!(secrets.asset == policy_asset, "Output is in asset {}, not L-BTC", secrets.asset);
+            return Ok(Amount::checked_from_sat(secrets.value)?);
+        }
+    }
+    anyhow::bail!("No blinding key unblinds output to {}", txout.script_pubkey)
+}
+
+/// Sum of the explicit fee outputs paid in `policy_asset`.
+pub fn fee(tx: &Transaction, policy_asset: AssetId) -> Result<Amount> {
+    let mut fee = Amount::ZERO;
+    for out in tx.output.iter().filter(|o| o.is_fee()) {
+        if out.asset.explicit() == Some(policy_asset) {
+            fee = fee.checked_add(Amount::checked_from_sat(out.value.explicit().context("Fee output without explicit value")?)?)?;
+        }
+    }
+    Ok(fee)
+}
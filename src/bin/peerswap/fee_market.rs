@@ -0,0 +1,215 @@
+//! A simulated fee market on regtest: a bitcoind wallet fills the mempool and
+//! blocks with transactions at a chosen feerate until `estimatesmartfee`
+//! reports it, so funding and swap opening fees come from the estimator rather
+//! than the fallback feerates.
+
+use anyhow::{Context, Result};
+use bitcoincore_rpc::bitcoin::Txid;
+use serde_json::json;
+use std::time::Duration;
+
+use super::bitcoin_rpc::AsyncBitcoinClient as BitcoinClient;
+use super::amount::Amount;
+use super::ids::Scid;
+use super::csv_claim::wait_for_opening_tx;
+use super::error::{Error, SwapRefusal};
+use super::recorder;
+use super::setup::{close_channels, node_id};
+use super::{cli, expect_swap_refused, SWAP_AMOUNT};
+
+/// bitcoind wallet the market transactions are paid from.
+const WALLET: &str = "feemarket";
+const TXS_PER_BLOCK: usize = 10;
+const MAX_ROUNDS: usize = 100;
+/// Conf targets that must report the market feerate before it counts as set.
+const CONF_TARGETS: [u16; 2] = [2, 6];
+/// How far an estimate or a paid feerate may be from the market, in percent.
+const TOLERANCE_PCT: u64 = 50;
+/// Capacity of the channel whose funding feerate is checked, which also tags it
+/// for closing.
+const FUNDING_AMOUNT: Amount = Amount::from_sat(200_000);
+/// What each market transaction sends back to the wallet.
+const SEND_AMOUNT: Amount = Amount::from_sat(100_000);
+/// Balance below which the wallet is topped up with coinbases.
+const MIN_WALLET_BALANCE: Amount = Amount::from_sat(1_000_000_000);
+
+/// Feerate at which the market settles when a scenario is done.
+pub const LOW_FEERATE_SAT_PER_VB: u64 = 2;
+pub const MEDIUM_FEERATE_SAT_PER_VB: u64 = 25;
+/// High enough that a swap's opening fee outweighs `SWAP_AMOUNT`.
+pub const HIGH_FEERATE_SAT_PER_VB: u64 = 1_000;
+
+/// Compares in sat/kvB, as bitcoind and lightningd report feerates, so
+/// fractional sat/vB rates are not truncated.
+fn within_tolerance(actual_sat_per_kvb: u64, target_sat_per_vb: u64) -> bool {
+    let target = target_sat_per_vb * 1000;
+    actual_sat_per_kvb * 100 >= target * (100 - TOLERANCE_PCT) && actual_sat_per_kvb * 100 <= target * (100 + TOLERANCE_PCT)
+}
+
+pub struct FeeMarket {
+    btc: BitcoinClient,
+    wallet: BitcoinClient,
+    mine_to: bitcoincore_rpc::bitcoin::Address,
+}
+
+impl FeeMarket {
+    /// Loads or creates the market wallet and funds it with mature coinbases.
+    pub async fn new(btc: &BitcoinClient) -> Result<Self> {
+        let loaded: Vec<String> = btc.rpc("listwallets", vec![]).await?;
+        if !loaded.iter().any(|w| w == WALLET)
+            && btc.rpc::<serde_json::Value>("loadwallet", vec![WALLET.into()]).await.is_err()
+        {
+            btc.rpc::<serde_json::Value>("createwallet", vec![WALLET.into()]).await?;
+        }
+        let wallet = btc.wallet(WALLET)?;
+        let mine_to = wallet
+            .rpc::<String>("getnewaddress", vec![])
+            .await?
+            .parse::<bitcoincore_rpc::bitcoin::Address<_>>()?
+            .assume_checked();
+        let balance = Amount::from_btc_str(&wallet.rpc::<serde_json::Value>("getbalance", vec![]).await?.to_string())?;
+        if balance < MIN_WALLET_BALANCE {
+            btc.generate_to_address(110, &mine_to).await?;
+        }
+        Ok(FeeMarket { btc: btc.clone(), wallet, mine_to })
+    }
+
+    async fn send_at(&self, feerate_sat_per_vb: u64) -> Result<Txid> {
+        let address = self.mine_to.to_string();
+        // sendtoaddress address amount comment comment_to subtractfee replaceable conf_target estimate_mode avoid_reuse fee_rate
+        let args = vec![
+            json!(address),
+            json!(SEND_AMOUNT.to_btc_string()?),
+            json!(""),
+            json!(""),
+            json!(false),
+            json!(true),
+            json!(null),
+            json!("unset"),
+            json!(null),
+            json!(feerate_sat_per_vb),
+        ];
+        let txid: String = self.wallet.rpc("sendtoaddress", args).await?;
+        Ok(txid.parse()?)
+    }
+
+    async fn estimate_sat_per_kvb(&self, conf_target: u16) -> Result<Option<u64>> {
+        Ok(self.btc.estimate_smart_fee(conf_target).await?.fee_rate.map(|r| r.to_sat()))
+    }
+
+    /// Mines blocks of transactions paying `feerate_sat_per_vb` until every
+    /// target in `CONF_TARGETS` estimates it.
+    pub async fn set(&self, feerate_sat_per_vb: u64) -> Result<()> {
+        for round in 1..=MAX_ROUNDS {
+            for _ in 0..TXS_PER_BLOCK {
+                self.send_at(feerate_sat_per_vb).await?;
+            }
+            self.btc.generate_to_address(1, &self.mine_to).await?;
+
+            let mut settled = true;
+            for target in CONF_TARGETS {
+                settled &= self.estimate_sat_per_kvb(target).await?.is_some_and(|e| within_tolerance(e, feerate_sat_per_vb));
+            }
+            if settled {
+                println!("Fee market at {} sat/vB after {} block(s)", feerate_sat_per_vb, round);
+                return Ok(());
+            }
+        }
+        anyhow::bail!("estimatesmartfee did not reach {} sat/vB in {} blocks", feerate_sat_per_vb, MAX_ROUNDS)
+    }
+
+    /// Feerate `txid` pays while it is in the mempool, rounded to the nearest sat/kvB.
+    pub async fn paid_sat_per_kvb(&self, txid: &Txid) -> Result<u64> {
+        let entry = self.btc.get_mempool_entry(txid).await?;
+        anyhow::ensure!(entry.vsize > 0, "Mempool entry {} has no size", txid);
+        Ok((entry.fees.base.to_sat() * 1000 + entry.vsize / 2) / entry.vsize)
+    }
+}
+
+/// Waits until lightningd in `container` has picked up an opening feerate
+/// within tolerance of `feerate_sat_per_vb`.
+async fn wait_for_node_feerate(container: &str, feerate_sat_per_vb: u64) -> Result<()> {
+    let mut last = None;
+    for _ in 0..60 {
+        let feerates = cli(container, &["feerates", "perkb"]).await?;
+        last = feerates["perkb"]["opening"].as_u64();
+        if last.is_some_and(|f| within_tolerance(f, feerate_sat_per_vb)) {
+            return Ok(());
+        }
+        recorder::sleep(Duration::from_secs(2)).await;
+    }
+    anyhow::bail!("{} opening feerate stuck at {:?} sat/kvB, market is {} sat/vB", container, last, feerate_sat_per_vb)
+}
+
+/// Opens a channel from `from` to `to` and checks its funding transaction
+/// pays the market feerate. Returns the funding txid.
+async fn check_funding_fee(market: &FeeMarket, from: &str, to: &str, feerate_sat_per_vb: u64) -> Result<Txid> {
+    let to_id = node_id(to).await?;
+    let txid: Txid = cli(from, &["fundchannel", &to_id.to_string(), &FUNDING_AMOUNT.to_sat()?.to_string()]).await?["txid"]
+        .as_str()
+        .context("No txid")?
+        .parse()?;
+    let paid = market.paid_sat_per_kvb(&txid).await?;
+    println!("Funding {} pays {} sat/kvB in a {} sat/vB market", txid, paid, feerate_sat_per_vb);
+    anyhow::ensure!(within_tolerance(paid, feerate_sat_per_vb), "Funding fee {} sat/kvB does not follow the market at {} sat/vB", paid, feerate_sat_per_vb);
+    Ok(txid)
+}
+
+/// Starts a BTC swap-in and checks its opening transaction pays the market
+/// feerate, then mines until the swap completes.
+#[tracing::instrument(skip_all, fields(node = initiator, scid, feerate_sat_per_vb, asset = "btc", direction = "swap_in", swap_id = tracing::field::Empty))]
+async fn check_opening_fee(market: &FeeMarket, initiator: &str, scid: Scid, feerate_sat_per_vb: u64) -> Result<()> {
+    let swap = cli(initiator, &["peerswap-swap-in", &scid.to_string(), &SWAP_AMOUNT.to_sat()?.to_string(), "btc", "10000"]).await?;
+    let swap_id = swap["id"].as_str().context("No swap id")?.to_string();
+    tracing::Span::current().record("swap_id", &swap_id);
+    let tx: bitcoincore_rpc::bitcoin::Transaction =
+        bitcoincore_rpc::bitcoin::consensus::deserialize(&hex::decode(wait_for_opening_tx(initiator, &swap_id).await?)?)?;
+    let paid = market.paid_sat_per_kvb(&tx.compute_txid()).await?;
+    println!("Swap {} opening pays {} sat/kvB in a {} sat/vB market", swap_id, paid, feerate_sat_per_vb);
+    anyhow::ensure!(within_tolerance(paid, feerate_sat_per_vb), "Opening fee {} sat/kvB does not follow the market at {} sat/vB", paid, feerate_sat_per_vb);
+
+    for _ in 0..30 {
+        market.btc.generate_to_address(1, &market.mine_to).await?;
+        recorder::sleep(Duration::from_secs(2)).await;
+        let status = cli(initiator, &["peerswap-getswap", &swap_id]).await?;
+        let state = status["current"].as_str().unwrap_or("");
+        tracing::debug!(state, "Polled swap");
+        if state == "State_ClaimedPreimage" || state == "State_ClaimedCoop" {
+            let fee = Amount::from_sat(status["data"]["opening_tx_fee"].as_u64().unwrap_or(0));
+            println!("Swap {} completed with opening fee {}", swap_id, fee);
+            return Ok(());
+        }
+    }
+    Err(Error::Timeout(format!("waiting for swap {}", swap_id)).into())
+}
+
+/// Checks that funding and swap opening fees follow a medium fee market, and
+/// that swaps are refused once fees are high. The channel opened to check the
+/// funding fee is closed again, along with any an earlier run left behind.
+/// Leaves the market at `LOW_FEERATE_SAT_PER_VB`.
+pub async fn run_fee_scenarios(btc: &BitcoinClient, initiator: &str, peer: &str, scid: Scid) -> Result<()> {
+    let market = FeeMarket::new(btc).await?;
+    let result = async {
+        close_channels(btc, initiator, peer, FUNDING_AMOUNT).await?;
+        market.set(MEDIUM_FEERATE_SAT_PER_VB).await?;
+        wait_for_node_feerate(initiator, MEDIUM_FEERATE_SAT_PER_VB).await?;
+        wait_for_node_feerate(peer, MEDIUM_FEERATE_SAT_PER_VB).await?;
+        check_funding_fee(&market, initiator, peer, MEDIUM_FEERATE_SAT_PER_VB).await?;
+        market.btc.generate_to_address(6, &market.mine_to).await?;
+        close_channels(btc, initiator, peer, FUNDING_AMOUNT).await?;
+        check_opening_fee(&market, initiator, scid, MEDIUM_FEERATE_SAT_PER_VB).await?;
+
+        market.set(HIGH_FEERATE_SAT_PER_VB).await?;
+        wait_for_node_feerate(initiator, HIGH_FEERATE_SAT_PER_VB).await?;
+        wait_for_node_feerate(peer, HIGH_FEERATE_SAT_PER_VB).await?;
+        let amount = SWAP_AMOUNT.to_sat()?.to_string();
+        expect_swap_refused(initiator, &["peerswap-swap-out", &scid.to_string(), &amount, "btc", "10000"], SwapRefusal::FeeTooHigh).await?;
+        expect_swap_refused(initiator, &["peerswap-swap-in", &scid.to_string(), &amount, "btc", "10000"], SwapRefusal::FeeTooHigh).await?;
+        println!("Swaps refused at {} sat/vB", HIGH_FEERATE_SAT_PER_VB);
+        Ok(())
+    }
+    .await;
+
+    market.set(LOW_FEERATE_SAT_PER_VB).await?;
+    result
+}
@@ -0,0 +1,217 @@
+//! Locates the PeerSwap HTLC output of an opening transaction by rebuilding its
+//! script from the swap record, instead of assuming it sits at `vout 0`.
+
+use anyhow::{Context, Result};
+use bitcoincore_rpc::bitcoin::opcodes::all::{
+    OP_CHECKSIG, OP_CHECKSIGVERIFY, OP_CSV, OP_ELSE, OP_ENDIF, OP_EQUAL, OP_EQUALVERIFY, OP_NOTIF, OP_SHA256, OP_SIZE,
+};
+use bitcoincore_rpc::bitcoin::script::{Builder, PushBytesBuf, ScriptBuf};
+use elements::secp256k1_zkp::Secp256k1;
+
+use super::amount::Amount;
+use super::csv_claim::{BTC_CSV, LBTC_CSV};
+use super::elements_tx::{self, BlindingKeys};
+use super::{cli, SwapResult};
+
+/// Parameters of a PeerSwap opening script. The maker funds the opening
+/// transaction and can take it back after `csv` blocks; the taker claims it with
+/// the preimage of `payment_hash`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SwapScript {
+    pub maker_pubkey: Vec<u8>,
+    pub taker_pubkey: Vec<u8>,
+    pub payment_hash: [u8; 32],
+    pub csv: u64,
+}
+
+impl SwapScript {
+    /// Witness script, following PeerSwap's `GetOpeningTxScript`:
+    ///
+    /// ```text
+    /// <maker> OP_CHECKSIG OP_NOTIF
+    ///     <maker> OP_CHECKSIGVERIFY <csv> OP_CHECKSEQUENCEVERIFY
+    /// OP_ELSE
+    ///     <taker> OP_CHECKSIGVERIFY OP_SIZE 32 OP_EQUALVERIFY OP_SHA256 <payment_hash> OP_EQUAL
+    /// OP_ENDIF
+    /// ```
+    pub fn witness_script(&self) -> Result<ScriptBuf> {
+        let maker = PushBytesBuf::try_from(self.maker_pubkey.clone())?;
+        let taker = PushBytesBuf::try_from(self.taker_pubkey.clone())?;
+        Ok(Builder::new()
+            .push_slice(&maker)
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_NOTIF)
+            .push_slice(&maker)
+            .push_opcode(OP_CHECKSIGVERIFY)
+            .push_int(self.csv as i64)
+            .push_opcode(OP_CSV)
+            .push_opcode(OP_ELSE)
+            .push_slice(&taker)
+            .push_opcode(OP_CHECKSIGVERIFY)
+            .push_opcode(OP_SIZE)
+            .push_slice([32u8])
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_SHA256)
+            .push_slice(self.payment_hash)
+            .push_opcode(OP_EQUAL)
+            .push_opcode(OP_ENDIF)
+            .into_script())
+    }
+
+    /// P2WSH output script. Elements uses the same v0 witness program form.
+    pub fn script_pubkey(&self) -> Result<ScriptBuf> {
+        Ok(ScriptBuf::new_p2wsh(&self.witness_script()?.wscript_hash()))
+    }
+}
+
+/// The HTLC output found in an opening transaction.
+#[derive(Clone, Debug)]
+pub struct LockedOutput {
+    pub vout: u32,
+    pub amount: Amount,
+    pub script: SwapScript,
+}
+
+fn pubkey(value: &serde_json::Value, field: &str) -> Result<Vec<u8>> {
+    let key = hex::decode(value["pubkey"].as_str().with_context(|| format!("No pubkey in {}", field))?)?;
+    anyhow::ensure!(key.len() == 33, "{} pubkey is {} bytes, expected 33", field, key.len());
+    Ok(key)
+}
+
+/// Builds the expected script from the swap record held by the node in `container`.
+pub async fn expected_script(container: &str, asset: &str, swap: &serde_json::Value) -> Result<SwapScript> {
+    // The swap-in initiator funds the opening transaction; on a swap-out it is the peer
+    let (maker_pubkey, taker_pubkey) = if swap["swap_in_request"].is_object() {
+        (pubkey(&swap["swap_in_request"], "swap_in_request")?, pubkey(&swap["swap_in_agreement"], "swap_in_agreement")?)
+    } else {
+        (pubkey(&swap["swap_out_agreement"], "swap_out_agreement")?, pubkey(&swap["swap_out_request"], "swap_out_request")?)
+    };
+
+    let payreq = swap["opening_tx_broadcasted"]["payreq"]
+        .as_str()
+        .context("No opening_tx_broadcasted payreq")?;
+    let decoded = cli(container, &["decode", payreq]).await?;
+    let payment_hash: [u8; 32] = hex::decode(decoded["payment_hash"].as_str().context("No payment_hash")?)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("payment_hash is not 32 bytes"))?;
+
+    Ok(SwapScript {
+        maker_pubkey,
+        taker_pubkey,
+        payment_hash,
+        csv: if asset == "lbtc" { LBTC_CSV } else { BTC_CSV },
+    })
+}
+
+/// Output scripts and amounts of an opening transaction. Confidential Liquid
+/// outputs are unblinded with the swap's blinding key or the wallet's keys;
+/// an output neither unblinds, or a value out of range, is an error.
+async fn tx_outputs(asset: &str, tx_hex: &str, swap: &serde_json::Value) -> Result<Vec<(ScriptBuf, Amount)>> {
+    match asset {
+        "btc" => {
+            let tx: bitcoincore_rpc::bitcoin::Transaction =
+                bitcoincore_rpc::bitcoin::consensus::deserialize(&hex::decode(tx_hex)?)?;
+            tx.output
+                .into_iter()
+                .map(|o| Ok((o.script_pubkey, Amount::checked_from_sat(o.value.to_sat())?)))
+                .collect()
+        }
+        _ => {
+            let tx = elements_tx::decode(tx_hex)?;
+            let policy_asset = elements_tx::policy_asset().await?;
+            let mut keys = BlindingKeys::from_wallet(elements_tx::LIQUID_WALLET).await?;
+            if let Some(key) = swap["opening_tx_broadcasted"]["blinding_key"].as_str()
+                && !key.is_empty()
+            {
+                keys = keys.with_key(key)?;
+            }
+            let secp = Secp256k1::verification_only();
+            tx.output
+                .iter()
+                .enumerate()
+                .map(|(vout, o)| {
+                    let amount = elements_tx::output_amount(&secp, o, &keys, policy_asset)
+                        .with_context(|| format!("Could not read the amount of output {}", vout))?;
+                    Ok((ScriptBuf::from_bytes(o.script_pubkey.to_bytes()), amount))
+                })
+                .collect()
+        }
+    }
+}
+
+/// Finds the HTLC output of a completed swap, checks it against the swap record
+/// and returns the amount actually locked.
+pub async fn locate(container: &str, asset: &str, result: &SwapResult) -> Result<LockedOutput> {
+    let script = expected_script(container, asset, &result.swap).await?;
+    let script_pubkey = script.script_pubkey()?;
+
+    let outputs = tx_outputs(asset, &result.opening_tx_hex, &result.swap).await?;
+    let mut matches = outputs.iter().enumerate().filter(|(_, (spk, _))| *spk == script_pubkey);
+    let (vout, (_, amount)) = matches
+        .next()
+        .context("No output in the opening transaction pays to the swap script")?;
+    anyhow::ensure!(matches.next().is_none(), "Several outputs pay to the swap script");
+
+    if let Some(script_out) = result.swap["opening_tx_broadcasted"]["script_out"].as_u64() {
+        anyhow::ensure!(
+            script_out == vout as u64,
+            "Swap record points at vout {} but the swap script is at vout {}", script_out, vout
+        );
+    }
+
+    Ok(LockedOutput {
+        vout: vout as u32,
+        amount: *amount,
+        script,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(csv: u64) -> SwapScript {
+        SwapScript {
+            maker_pubkey: [vec![0x02], vec![0x11; 32]].concat(),
+            taker_pubkey: [vec![0x03], vec![0x22; 32]].concat(),
+            payment_hash: [0x33; 32],
+            csv,
+        }
+    }
+
+    /// The script byte by byte, with `csv` already encoded as a push.
+    fn expected(csv_push: &[u8]) -> Vec<u8> {
+        let maker = [vec![0x02], vec![0x11; 32]].concat();
+        let taker = [vec![0x03], vec![0x22; 32]].concat();
+        [
+            &[0x21][..], &maker, &[0xac, 0x64, 0x21], &maker, &[0xad], csv_push, &[0xb2, 0x67, 0x21], &taker,
+            &[0xad, 0x82, 0x01, 0x20, 0x88, 0xa8, 0x20], &[0x33; 32], &[0x87, 0x68],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn builds_peerswap_opening_script() {
+        // 1008 is pushed as two little-endian bytes, 60 as one
+        assert_eq!(script(BTC_CSV).witness_script().unwrap().to_bytes(), expected(&[0x02, 0xf0, 0x03]));
+        assert_eq!(script(LBTC_CSV).witness_script().unwrap().to_bytes(), expected(&[0x01, 0x3c]));
+    }
+
+    #[test]
+    fn pays_to_witness_script_hash() {
+        let s = script(BTC_CSV);
+        let spk = s.script_pubkey().unwrap();
+        assert!(spk.is_p2wsh());
+        assert_eq!(spk, ScriptBuf::new_p2wsh(&s.witness_script().unwrap().wscript_hash()));
+        assert_ne!(spk, script(LBTC_CSV).script_pubkey().unwrap());
+    }
+
+    #[test]
+    fn reads_pubkeys_from_swap_messages() {
+        let key = hex::encode([vec![0x02], vec![0x11; 32]].concat());
+        let message = serde_json::json!({ "pubkey": key });
+        assert_eq!(pubkey(&message, "swap_in_request").unwrap().len(), 33);
+        assert!(pubkey(&serde_json::json!({ "pubkey": "02ab" }), "swap_in_request").is_err());
+        assert!(pubkey(&serde_json::json!({}), "swap_in_request").is_err());
+    }
+}
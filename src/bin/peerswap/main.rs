@@ -0,0 +1,619 @@
+use anyhow::{Context, Result};
+use bitcoin_rpc::AsyncBitcoinClient as BitcoinClient;
+use amount::Amount;
+use error::{Error, SwapRefusal};
+use gl_client_tryout::{amount, bitcoin_rpc, error, ids, metrics, recorder, setup_report, telemetry};
+use ids::{NodeId, Scid};
+
+mod concurrency;
+mod csv_claim;
+mod discovery;
+mod elements_tx;
+mod fee_market;
+mod htlc;
+mod policy;
+mod premium;
+mod process_env;
+mod rebalancer;
+mod regtest_env;
+mod reorg;
+mod setup;
+
+/// Runs `elements-cli` with `args`, handing it the RPC password on stdin.
+/// Returns its stdout.
+#[tracing::instrument(name = "rpc", skip_all, fields(node = "elementsd", method = args.iter().find(|a| !a.starts_with('-')).copied().unwrap_or_default()))]
+async fn elements_cli_output(args: &[&str]) -> error::Result<String> {
+    let method = args.iter().find(|a| !a.starts_with('-')).copied().unwrap_or_default();
+    telemetry::rpc(recorder::call("elements-cli", method, args, async {
+        let mut child = regtest_env::elements_cli_command()
+            .args(args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().context("No elements-cli stdin")?;
+        tokio::io::AsyncWriteExt::write_all(&mut stdin, format!("{}\n", regtest_env::rpc_password()?).as_bytes()).await?;
+        drop(stdin);
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(Error::from_cli_output("elements-cli", method, &output).into());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }))
+    .await
+    .map_err(Error::classify)
+}
+
+async fn elements_cli(args: &[&str]) -> error::Result<serde_json::Value> {
+    let output = elements_cli_output(args).await?;
+    Ok(serde_json::from_str(&output).unwrap_or(serde_json::Value::Null))
+}
+
+async fn get_channel_balance(container: &str, scid: Scid) -> Result<Amount> {
+    let funds = cli(container, &["listfunds"]).await?;
+    let scid = scid.to_string();
+    Ok(Amount::from_msat(funds["channels"]
+        .as_array()
+        .and_then(|chs| chs.iter().find(|c| c["short_channel_id"].as_str() == Some(&scid)))
+        .and_then(|c| c["our_amount_msat"].as_u64())
+        .unwrap_or(0)))
+}
+
+/// Balances and peers of the node in `container`, for the metrics endpoint.
+async fn node_snapshot(container: &str) -> Result<metrics::NodeSnapshot> {
+    let funds = cli(container, &["listfunds"]).await?;
+    let peers = cli(container, &["listpeers"]).await?;
+    let mut snapshot = metrics::NodeSnapshot { node: container.to_string(), ..Default::default() };
+    for output in funds["outputs"].as_array().into_iter().flatten() {
+        let status = output["status"].as_str().unwrap_or("unknown").to_string();
+        let sat = output["amount_msat"].as_u64().unwrap_or(0) / 1000;
+        match snapshot.onchain_sat.iter_mut().find(|(s, _)| *s == status) {
+            Some((_, total)) => *total += sat,
+            None => snapshot.onchain_sat.push((status, sat)),
+        }
+    }
+    for ch in funds["channels"].as_array().into_iter().flatten() {
+        let local_msat = ch["our_amount_msat"].as_u64().unwrap_or(0);
+        snapshot.channels.push(metrics::ChannelBalance {
+            peer: ch["peer_id"].as_str().unwrap_or_default().to_string(),
+            scid: ch["short_channel_id"].as_str().unwrap_or_default().to_string(),
+            local_msat,
+            remote_msat: ch["amount_msat"].as_u64().unwrap_or(0).saturating_sub(local_msat),
+        });
+    }
+    for peer in peers["peers"].as_array().into_iter().flatten() {
+        snapshot.peers.push((peer["id"].as_str().unwrap_or_default().to_string(), peer["connected"].as_bool().unwrap_or(false)));
+    }
+    Ok(snapshot)
+}
+
+#[tracing::instrument(name = "rpc", skip_all, fields(node = container, method = args.first().copied().unwrap_or_default()))]
+async fn cli(container: &str, args: &[&str]) -> error::Result<serde_json::Value> {
+    let method = args.first().copied().unwrap_or_default();
+    telemetry::rpc(recorder::call(&format!("lightning-cli:{}", container), method, args, async {
+        let output = regtest_env::lightning_cli_command(container)?
+            .args(args)
+            .output().await?;
+        if !output.status.success() {
+            return Err(Error::from_cli_output("lightning-cli", method, &output).into());
+        }
+        Ok(serde_json::from_slice(&output.stdout)?)
+    }))
+    .await
+    .map_err(Error::classify)
+}
+
+async fn newaddr(container: &str) -> Result<bitcoincore_rpc::bitcoin::Address> {
+    let resp = cli(container, &["newaddr"]).await?;
+    Ok(resp["bech32"].as_str().context("No bech32")?
+        .parse::<bitcoincore_rpc::bitcoin::Address<_>>()?.assume_checked())
+}
+
+/// A regtest address no node's wallet watches, for blocks whose coinbase must
+/// not show up in a balance being checked: P2WSH of `OP_TRUE`.
+fn neutral_address() -> bitcoincore_rpc::bitcoin::Address {
+    let script = bitcoincore_rpc::bitcoin::ScriptBuf::from_bytes(vec![0x51]);
+    bitcoincore_rpc::bitcoin::Address::p2wsh(&script, bitcoincore_rpc::bitcoin::Network::Regtest)
+}
+
+async fn invoice(container: &str, amount: Amount) -> Result<String> {
+    let label = recorder::unique_label("inv").await?;
+    let resp = cli(container, &["invoice", &amount.to_msat().to_string(), &label, "test"]).await?;
+    Ok(resp["bolt11"].as_str().context("No bolt11")?.to_string())
+}
+
+async fn set_premium_rate(container: &str, asset: &str, ppm_swap_out: u64, ppm_swap_in: u64) -> Result<()> {
+    cli(container, &["peerswap-updateglobalpremiumrate", asset, "swap_out", &ppm_swap_out.to_string()]).await?;
+    cli(container, &["peerswap-updateglobalpremiumrate", asset, "swap_in", &ppm_swap_in.to_string()]).await?;
+    Ok(())
+}
+
+async fn liquid_newaddr() -> Result<String> {
+    Ok(elements_cli_output(&["getnewaddress"]).await?.trim().to_string())
+}
+
+async fn liquid_generate(blocks: u64) -> Result<()> {
+    let addr = liquid_newaddr().await?;
+    elements_cli(&["generatetoaddress", &blocks.to_string(), &addr]).await?;
+    Ok(())
+}
+
+async fn liquid_send(to_addr: &str, amount: Amount) -> Result<String> {
+    let txid = elements_cli_raw(&["-rpcwallet=peerswap", "sendtoaddress", to_addr, &amount.to_btc_string()?]).await?;
+    Ok(txid)
+}
+
+async fn elements_cli_raw(args: &[&str]) -> error::Result<String> {
+    Ok(elements_cli_output(args).await?.trim().to_string())
+}
+
+async fn liquid_fund_wallet() -> Result<()> {
+    // With -anyonecanspendaremine=1, genesis coins are spendable via sendtoaddress
+    let addr = liquid_newaddr().await?;
+    liquid_send(&addr, Amount::from_sat(100 * amount::SAT_PER_BTC)).await?;
+    liquid_generate(1).await?;
+    println!("Funded wallet with 100 L-BTC");
+    Ok(())
+}
+
+async fn peerswap_lbtc_addr(container: &str) -> Result<String> {
+    let resp = cli(container, &["peerswap-lbtc-getaddress"]).await?;
+    resp["address"].as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| anyhow::anyhow!("No address in response: {:?}", resp))
+}
+
+const SWAP_AMOUNT: Amount = Amount::from_sat(100_000);
+
+struct SwapResult {
+    swap_id: String,
+    /// Final state, as `peerswap-getswap` reported it.
+    state: String,
+    onchain_fee: Amount,
+    premium: i64,
+    opening_tx_hex: String,
+    /// The swap record's `data`, for checks against the on-chain transaction.
+    swap: serde_json::Value,
+}
+
+#[tracing::instrument(skip_all, fields(node = container, scid = %scid, asset = "btc", direction = "swap_out", swap_id = tracing::field::Empty))]
+async fn swap_out(
+    btc: &BitcoinClient,
+    container: &str,
+    scid: Scid,
+    amount: Amount,
+    max_premium_ppm: u64,
+    mine_to: &bitcoincore_rpc::bitcoin::Address,
+) -> Result<SwapResult> {
+    let swap = cli(container, &[
+        "peerswap-swap-out", &scid.to_string(), &amount.to_sat()?.to_string(), "btc", &max_premium_ppm.to_string(),
+    ]).await?;
+    let swap_id = swap["id"].as_str().context("No swap id")?;
+    tracing::Span::current().record("swap_id", swap_id);
+    for _ in 0..30 {
+        btc.generate_to_address(1, mine_to).await?;
+        recorder::sleep(std::time::Duration::from_secs(2)).await;
+        let status = cli(container, &["peerswap-getswap", swap_id]).await?;
+        let state = status["current"].as_str().unwrap_or("");
+        tracing::debug!(state, "Polled swap");
+        if state == "State_ClaimedPreimage" || state == "State_ClaimedCoop" {
+            tracing::info!(state, "Swap completed");
+            return Ok(SwapResult {
+                swap_id: swap_id.to_string(),
+                state: state.to_string(),
+                onchain_fee: Amount::from_sat(status["data"]["opening_tx_fee"].as_u64().unwrap_or(0)),
+                premium: status["data"]["swap_out_agreement"]["premium"].as_i64().unwrap_or(0),
+                opening_tx_hex: status["data"]["opening_tx_hex"].as_str().unwrap_or("").to_string(),
+                swap: status["data"].clone(),
+            });
+        }
+    }
+    Err(Error::Timeout("waiting for swap".into()).into())
+}
+
+#[tracing::instrument(skip_all, fields(node = container, scid = %scid, asset = "btc", direction = "swap_in", swap_id = tracing::field::Empty))]
+async fn swap_in(
+    btc: &BitcoinClient,
+    container: &str,
+    scid: Scid,
+    amount: Amount,
+    max_premium_ppm: u64,
+    mine_to: &bitcoincore_rpc::bitcoin::Address,
+) -> Result<SwapResult> {
+    let swap = cli(container, &[
+        "peerswap-swap-in", &scid.to_string(), &amount.to_sat()?.to_string(), "btc", &max_premium_ppm.to_string()
+    ]).await?;
+    let swap_id = swap["id"].as_str().context("No swap id")?.to_string();
+    tracing::Span::current().record("swap_id", swap_id.as_str());
+    for _ in 0..30 {
+        btc.generate_to_address(1, mine_to).await?;
+        recorder::sleep(std::time::Duration::from_secs(2)).await;
+        let status = cli(container, &["peerswap-getswap", &swap_id]).await?;
+        let state = status["current"].as_str().unwrap_or("");
+        tracing::debug!(state, "Polled swap");
+        if state == "State_ClaimedPreimage" || state == "State_ClaimedCoop" {
+            tracing::info!(state, "Swap completed");
+            return Ok(SwapResult {
+                swap_id: swap_id.to_string(),
+                state: state.to_string(),
+                onchain_fee: Amount::from_sat(status["data"]["opening_tx_fee"].as_u64().unwrap_or(0)),
+                premium: status["data"]["swap_in_agreement"]["premium"].as_i64().unwrap_or(0),
+                opening_tx_hex: status["data"]["opening_tx_hex"].as_str().unwrap_or("").to_string(),
+                swap: status["data"].clone(),
+            });
+        }
+    }
+    Err(Error::Timeout("waiting for swap".into()).into())
+}
+
+/// Starts a swap with `args` and expects it to be refused, either by the command
+/// itself or by a cancel, with a message accepted by `is_expected`.
+#[tracing::instrument(skip_all, fields(node = container, command = args.first().copied().unwrap_or_default(), swap_id = tracing::field::Empty))]
+async fn expect_swap_refused(container: &str, args: &[&str], expected: SwapRefusal) -> Result<()> {
+    let swap = match cli(container, args).await {
+        Err(Error::SwapRefused { reason, .. }) if reason == expected => return Ok(()),
+        Err(e) => anyhow::bail!("Unexpected swap error: {}", e),
+        Ok(swap) => swap,
+    };
+    let swap_id = swap["id"].as_str().context("No swap id")?.to_string();
+    tracing::Span::current().record("swap_id", swap_id.as_str());
+    for _ in 0..30 {
+        recorder::sleep(std::time::Duration::from_secs(1)).await;
+        let status = cli(container, &["peerswap-getswap", &swap_id]).await?;
+        let state = status["current"].as_str().unwrap_or("");
+        tracing::debug!(state, "Polled swap");
+        if state == "State_SwapCanceled" {
+            let msg = status["data"]["cancel_message"].as_str().unwrap_or("");
+            anyhow::ensure!(SwapRefusal::parse(msg) == Some(expected), "Swap canceled for another reason: {}", msg);
+            anyhow::ensure!(
+                status["data"]["opening_tx_hex"].as_str().unwrap_or("").is_empty(),
+                "Canceled swap has an opening transaction"
+            );
+            return Ok(());
+        }
+        anyhow::ensure!(
+            state != "State_ClaimedPreimage" && state != "State_ClaimedCoop",
+            "Swap that should be refused completed: {}", state
+        );
+    }
+    Err(Error::Timeout(format!("waiting for swap {} to be canceled", swap_id)).into())
+}
+
+async fn liquid_get_tx_fee(tx_hex: &str) -> Result<Amount> {
+    let tx = elements_tx::decode(tx_hex)?;
+    elements_tx::fee(&tx, elements_tx::policy_asset().await?)
+}
+
+#[tracing::instrument(skip_all, fields(node = container, scid = %scid, asset = "lbtc", direction = "swap_out", swap_id = tracing::field::Empty))]
+async fn swap_out_lbtc(
+    container: &str,
+    scid: Scid,
+    amount: Amount,
+    max_premium_ppm: u64,
+) -> Result<SwapResult> {
+    let swap = cli(container, &[
+        "peerswap-swap-out", &scid.to_string(), &amount.to_sat()?.to_string(), "lbtc", &max_premium_ppm.to_string(),
+    ]).await?;
+    let swap_id = swap["id"].as_str().context("No swap id")?;
+    tracing::Span::current().record("swap_id", swap_id);
+    for _ in 0..30 {
+        liquid_generate(1).await?;
+        recorder::sleep(std::time::Duration::from_secs(2)).await;
+        let status = cli(container, &["peerswap-getswap", swap_id]).await?;
+        let state = status["current"].as_str().unwrap_or("");
+        tracing::debug!(state, "Polled swap");
+        if state == "State_ClaimedPreimage" || state == "State_ClaimedCoop" {
+            tracing::info!(state, "Swap completed");
+            return Ok(SwapResult {
+                swap_id: swap_id.to_string(),
+                state: state.to_string(),
+                onchain_fee: Amount::from_sat(status["data"]["opening_tx_fee"].as_u64().unwrap_or(0)),
+                premium: status["data"]["swap_out_agreement"]["premium"].as_i64().unwrap_or(0),
+                opening_tx_hex: status["data"]["opening_tx_hex"].as_str().unwrap_or("").to_string(),
+                swap: status["data"].clone(),
+            });
+        }
+    }
+    Err(Error::Timeout("waiting for swap".into()).into())
+}
+
+#[tracing::instrument(skip_all, fields(node = container, scid = %scid, asset = "lbtc", direction = "swap_in", swap_id = tracing::field::Empty))]
+async fn swap_in_lbtc(
+    container: &str,
+    scid: Scid,
+    amount: Amount,
+    max_premium_ppm: u64,
+) -> Result<SwapResult> {
+    let swap = cli(container, &[
+        "peerswap-swap-in", &scid.to_string(), &amount.to_sat()?.to_string(), "lbtc", &max_premium_ppm.to_string()
+    ]).await?;
+    let swap_id = swap["id"].as_str().context("No swap id")?.to_string();
+    tracing::Span::current().record("swap_id", swap_id.as_str());
+    for _ in 0..30 {
+        liquid_generate(1).await?;
+        recorder::sleep(std::time::Duration::from_secs(2)).await;
+        let status = cli(container, &["peerswap-getswap", &swap_id]).await?;
+        let state = status["current"].as_str().unwrap_or("");
+        tracing::debug!(state, "Polled swap");
+        if state == "State_ClaimedPreimage" || state == "State_ClaimedCoop" {
+            tracing::info!(state, "Swap completed");
+            return Ok(SwapResult {
+                swap_id: swap_id.to_string(),
+                state: state.to_string(),
+                onchain_fee: Amount::from_sat(status["data"]["opening_tx_fee"].as_u64().unwrap_or(0)),
+                premium: status["data"]["swap_in_agreement"]["premium"].as_i64().unwrap_or(0),
+                opening_tx_hex: status["data"]["opening_tx_hex"].as_str().unwrap_or("").to_string(),
+                swap: status["data"].clone(),
+            });
+        }
+    }
+    Err(Error::Timeout("waiting for swap".into()).into())
+}
+
+async fn open_channel(
+    btc: &BitcoinClient,
+    from: &str,
+    to: NodeId,
+    amount: Amount,
+    mine_to: &bitcoincore_rpc::bitcoin::Address,
+) -> Result<Scid> {
+    let funding_txid = cli(from, &["fundchannel", &to.to_string(), &amount.to_sat()?.to_string()]).await?["txid"]
+        .as_str()
+        .context("No txid")?
+        .to_string();
+    await_lockin(btc, from, &funding_txid, mine_to).await
+}
+
+/// Mines the channel funded by `funding_txid` to lock-in and returns its scid
+/// once `from` reports it active.
+async fn await_lockin(
+    btc: &BitcoinClient,
+    from: &str,
+    funding_txid: &str,
+    mine_to: &bitcoincore_rpc::bitcoin::Address,
+) -> Result<Scid> {
+    btc.generate_to_address(6, mine_to).await?;
+    for _ in 0..60 {
+        if let Some(ch) = cli(from, &["listfunds"]).await?["channels"]
+            .as_array()
+            .and_then(|chs| chs.iter().find(|c| c["funding_txid"].as_str() == Some(funding_txid)))
+            && ch["state"].as_str() == Some("CHANNELD_NORMAL")
+        {
+            return ch["short_channel_id"].as_str().context("No scid")?.parse();
+        }
+        recorder::sleep(std::time::Duration::from_secs(1)).await;
+    }
+    Err(Error::Timeout("waiting for channel".into()).into())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let _telemetry = telemetry::init("peerswap")?;
+    recorder::init_from_env()?;
+
+    // `peerswap --fresh` runs the scenarios against a stack of its own instead of the one already running
+    if std::env::args().any(|a| a == "--fresh") {
+        anyhow::ensure!(!recorder::replaying(), "--fresh starts a real stack and cannot replay RPC traffic");
+        let stack = regtest_env::RegtestEnv::up().await?;
+        let result = async {
+            let btc = BitcoinClient::new(&stack.bitcoind_url(), regtest_env::rpc_auth()?)?;
+            stack.wait_healthy(&btc).await?;
+            run_scenarios(&btc).await
+        }
+        .await;
+        stack.down(result.is_err()).await?;
+        return result;
+    }
+
+    // `peerswap --local` runs the scenarios against bitcoind, elementsd and lightningd child processes
+    if std::env::args().any(|a| a == "--local") {
+        anyhow::ensure!(!recorder::replaying(), "--local starts real daemons and cannot replay RPC traffic");
+        let stack = process_env::ProcessEnv::start().await?;
+        let result = async {
+            let btc = BitcoinClient::new(&stack.bitcoind_url(), regtest_env::rpc_auth()?)?;
+            regtest_env::wait_healthy(&btc).await?;
+            run_scenarios(&btc).await
+        }
+        .await;
+        stack.down(result.is_err()).await?;
+        return result;
+    }
+
+    // `peerswap init-config` writes the credential files the long-lived stack mounts
+    if std::env::args().nth(1).as_deref() == Some("init-config") {
+        return regtest_env::init_config();
+    }
+
+    // Replay never contacts bitcoind, so it needs no rpc.conf
+    let auth = if recorder::replaying() { bitcoincore_rpc::Auth::None } else { regtest_env::rpc_auth()? };
+    let btc = BitcoinClient::new("http://127.0.0.1:18443", auth)?;
+
+    // `peerswap rebalance [--dry-run] [--metrics <addr>]` keeps Alice's channels balanced instead of running the scenarios
+    if std::env::args().nth(1).as_deref() == Some("rebalance") {
+        println!("Block height: {}", btc.get_block_count().await?);
+        let config = rebalancer::RebalancerConfig {
+            dry_run: std::env::args().any(|a| a == "--dry-run"),
+            ..Default::default()
+        };
+        if let Some(addr) = metrics::addr_from_args()? {
+            anyhow::ensure!(!recorder::replaying(), "--metrics polls live nodes and cannot replay RPC traffic");
+            metrics::serve(addr)?;
+            let interval = config.poll_interval;
+            tokio::spawn(async move {
+                loop {
+                    match node_snapshot("alice").await {
+                        Ok(snapshot) => metrics::update_nodes(&[snapshot]),
+                        Err(e) => tracing::warn!(error = %format!("{:#}", e), "Reading node state for metrics failed"),
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+            });
+        }
+        let mine_to = newaddr("alice").await?;
+        return rebalancer::Rebalancer::new(&btc, "alice", config).run(&mine_to, None).await;
+    }
+
+    run_scenarios(&btc).await
+}
+
+async fn run_scenarios(btc: &BitcoinClient) -> Result<()> {
+    println!("Block height: {}", btc.get_block_count().await?);
+
+    let mut report = setup_report::SetupReport::default();
+
+    // Commenting out premium rates to use defaults (swap_out=2000, swap_in=0)
+    // set_premium_rate("alice", "btc", 6100, 7100).await?;
+    // set_premium_rate("bob", "btc", 4100, 5100).await?;
+
+    // Fund Alice
+    let alice_addr = newaddr("alice").await?;
+    setup::ensure_btc(btc, &mut report, "alice", Amount::from_sat(amount::SAT_PER_BTC)).await?;
+
+    // Connect and open channel
+    let scid = setup::ensure_channels(btc, &mut report, "alice", "bob", 1, Amount::from_sat(500_000), &alice_addr).await?.remove(0);
+    println!("Channel active: {}", scid);
+
+    // Fund Bob (needs on-chain for swap)
+    setup::ensure_btc(btc, &mut report, "bob", Amount::from_sat(amount::SAT_PER_BTC)).await?;
+
+    // Give Bob channel balance
+    setup::ensure_channel_balance(&mut report, "alice", "bob", scid, Amount::from_sat(200_000)).await?;
+
+    // Swap-out: Alice gets on-chain BTC, Bob gets lightning
+    let alice_before = get_channel_balance("alice", scid).await?;
+    let bob_before = get_channel_balance("bob", scid).await?;
+    println!("Before: Alice={} Bob={}", alice_before, bob_before);
+
+    let result = swap_out(btc, "alice", scid, SWAP_AMOUNT, 10_000, &alice_addr).await?;
+    println!("Swap completed! onchain_fee={} premium={}", result.onchain_fee, result.premium);
+    let locked = htlc::locate("alice", "btc", &result).await?;
+    println!("On-chain sent: {} at vout {} (amount={}, payment_hash={})",
+        locked.amount, locked.vout, SWAP_AMOUNT, hex::encode(locked.script.payment_hash));
+    anyhow::ensure!(locked.amount == SWAP_AMOUNT, "On-chain amount mismatch: {} != {}", locked.amount, SWAP_AMOUNT);
+
+    let alice_after = get_channel_balance("alice", scid).await?;
+    let bob_after = get_channel_balance("bob", scid).await?;
+    println!("After:  Alice={} Bob={}", alice_after, bob_after);
+    println!("Delta:  Alice={:+} Bob={:+}",
+        alice_after.sat_delta(alice_before),
+        bob_after.sat_delta(bob_before));
+
+    // Swap-in: Alice gets lightning, Bob gets on-chain BTC
+    let alice_before = get_channel_balance("alice", scid).await?;
+    let bob_before = get_channel_balance("bob", scid).await?;
+    println!("Before: Alice={} Bob={}", alice_before, bob_before);
+
+    let result = swap_in(btc, "alice", scid, SWAP_AMOUNT, 10_000, &alice_addr).await?;
+    println!("Swap completed! onchain_fee={} premium={}", result.onchain_fee, result.premium);
+
+    // Verify on-chain amount = swap amount + premium
+    let locked = htlc::locate("alice", "btc", &result).await?;
+    println!("On-chain sent: {} at vout {} (amount={} + premium={})", locked.amount, locked.vout, SWAP_AMOUNT, result.premium);
+    let expected = SWAP_AMOUNT.to_sat()?.0 as i64 + result.premium;
+    anyhow::ensure!(locked.amount.to_sat()?.0 as i64 == expected, "On-chain amount mismatch: {} != {}", locked.amount, expected);
+
+    let alice_after = get_channel_balance("alice", scid).await?;
+    let bob_after = get_channel_balance("bob", scid).await?;
+    println!("After:  Alice={} Bob={}", alice_after, bob_after);
+    println!("Delta:  Alice={:+} Bob={:+}",
+             alice_after.sat_delta(alice_before),
+             bob_after.sat_delta(bob_before));
+
+    // === Liquid Swaps ===
+    println!("\n=== Liquid Swaps ===");
+
+    // Initialize Liquid
+    setup::ensure_liquid_wallet(&mut report, Amount::from_sat(10 * amount::SAT_PER_BTC)).await?;
+
+    // Fund Alice and Bob with L-BTC
+    setup::ensure_lbtc(&mut report, "alice", Amount::from_sat(amount::SAT_PER_BTC)).await?;
+    setup::ensure_lbtc(&mut report, "bob", Amount::from_sat(amount::SAT_PER_BTC)).await?;
+
+    // L-BTC Swap-out: Alice gets L-BTC, Bob gets lightning
+    let alice_before = get_channel_balance("alice", scid).await?;
+    let bob_before = get_channel_balance("bob", scid).await?;
+    println!("Before: Alice={} Bob={}", alice_before, bob_before);
+
+    let result = swap_out_lbtc("alice", scid, SWAP_AMOUNT, 10_000).await?;
+    let tx_fee = liquid_get_tx_fee(&result.opening_tx_hex).await?;
+    println!("L-BTC Swap-out completed! onchain_fee={} premium={}", tx_fee, result.premium);
+    anyhow::ensure!(tx_fee == result.onchain_fee, "Opening fee mismatch: {} != {}", tx_fee, result.onchain_fee);
+    let locked = htlc::locate("alice", "lbtc", &result).await?;
+    println!("On-chain sent: {} at vout {} (amount={})", locked.amount, locked.vout, SWAP_AMOUNT);
+    anyhow::ensure!(locked.amount == SWAP_AMOUNT, "On-chain amount mismatch: {} != {}", locked.amount, SWAP_AMOUNT);
+
+    let alice_after = get_channel_balance("alice", scid).await?;
+    let bob_after = get_channel_balance("bob", scid).await?;
+    println!("After:  Alice={} Bob={}", alice_after, bob_after);
+    println!("Delta:  Alice={:+} Bob={:+}",
+        alice_after.sat_delta(alice_before),
+        bob_after.sat_delta(bob_before));
+
+    // L-BTC Swap-in: Alice gets lightning, Bob gets L-BTC
+    let alice_before = get_channel_balance("alice", scid).await?;
+    let bob_before = get_channel_balance("bob", scid).await?;
+    println!("Before: Alice={} Bob={}", alice_before, bob_before);
+
+    let result = swap_in_lbtc("alice", scid, SWAP_AMOUNT, 10_000).await?;
+    let tx_fee = liquid_get_tx_fee(&result.opening_tx_hex).await?;
+    println!("L-BTC Swap-in completed! onchain_fee={} premium={}", tx_fee, result.premium);
+    anyhow::ensure!(tx_fee == result.onchain_fee, "Opening fee mismatch: {} != {}", tx_fee, result.onchain_fee);
+    let locked = htlc::locate("alice", "lbtc", &result).await?;
+    println!("On-chain sent: {} at vout {} (amount={} + premium={})", locked.amount, locked.vout, SWAP_AMOUNT, result.premium);
+    let expected = SWAP_AMOUNT.to_sat()?.0 as i64 + result.premium;
+    anyhow::ensure!(locked.amount.to_sat()?.0 as i64 == expected, "On-chain amount mismatch: {} != {}", locked.amount, expected);
+
+    let alice_after = get_channel_balance("alice", scid).await?;
+    let bob_after = get_channel_balance("bob", scid).await?;
+    println!("After:  Alice={} Bob={}", alice_after, bob_after);
+    println!("Delta:  Alice={:+} Bob={:+}",
+             alice_after.sat_delta(alice_before),
+             bob_after.sat_delta(bob_before));
+
+    // === Premium Rates ===
+    println!("\n=== Premium Rates ===");
+    premium::run_matrix(btc, "alice", "bob", scid, &alice_addr).await?;
+    println!("Premium matrix passed");
+
+    // === Peer Outages ===
+    println!("\n=== Peer Outages ===");
+    csv_claim::run_all(btc, "alice", "bob", scid).await?;
+    println!("CSV claims recovered all funds");
+
+    // === Peer Policy ===
+    println!("\n=== Peer Policy ===");
+    policy::run_policy_scenarios("alice", "bob", scid).await?;
+    println!("Policy checks passed");
+
+    // === Reorgs ===
+    println!("\n=== Reorgs ===");
+    reorg::run_funding_reorg(btc, "alice", "bob", Amount::from_sat(300_000), &alice_addr).await?;
+    reorg::run_opening_reorg(btc, "alice", scid, &alice_addr).await?;
+    println!("Channel and swap recovered from reorgs");
+
+    // === Peer Discovery ===
+    println!("\n=== Peer Discovery ===");
+    for peer in discovery::list_peers("alice").await? {
+        println!("PeerSwap peer {}: assets={:?} premiums={:?}", peer.node_id, peer.supported_assets, peer.premiums);
+    }
+    let (out, _) = discovery::swap_any(btc, "alice", premium::Direction::SwapOut, SWAP_AMOUNT, None, 10_000, &alice_addr).await?;
+    let (back, _) = discovery::swap_any(btc, "alice", premium::Direction::SwapIn, SWAP_AMOUNT, Some(out.asset), 10_000, &alice_addr).await?;
+    println!("Swapped out over {} and back in over {}", out.channel.scid, back.channel.scid);
+
+    // === Concurrent Swaps ===
+    println!("\n=== Concurrent Swaps ===");
+    concurrency::fund_node(btc, &mut report, "carol").await?;
+    let channels = concurrency::open_channels(btc, &mut report, &[("alice", "bob", 2), ("alice", "carol", 1)], Amount::from_sat(1_000_000), &alice_addr).await?;
+    let specs = concurrency::full_matrix(&channels, SWAP_AMOUNT);
+    concurrency::run(btc, &specs, concurrency::MiningMode::Central, &neutral_address()).await?;
+    concurrency::run(btc, &specs, concurrency::MiningMode::PerSwap, &neutral_address()).await?;
+    println!("All concurrent swaps reconciled");
+
+    // === Fee Market ===
+    println!("\n=== Fee Market ===");
+    fee_market::run_fee_scenarios(btc, "alice", "bob", scid).await?;
+    println!("Fees followed the simulated market");
+
+    report.print();
+    Ok(())
+}
@@ -0,0 +1,251 @@
+//! Typed model of PeerSwap's `policy.conf`, and scenarios for peer allowlisting
+//! and the minimum swap amount.
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::path::Path;
+use tokio::fs;
+use std::str::FromStr;
+
+use super::amount::Amount;
+use super::error::SwapRefusal;
+use super::ids::{NodeId, Scid};
+use super::setup::node_id;
+use super::regtest_env::policy_path;
+use super::{cli, expect_swap_refused, SWAP_AMOUNT};
+
+const ACCEPT_ALL_PEERS: &str = "accept_all_peers";
+const MIN_SWAP_AMOUNT_MSAT: &str = "min_swap_amount_msat";
+const ALLOW_NEW_SWAPS: &str = "allow_new_swaps";
+const ALLOWLISTED_PEERS: &str = "allowlisted_peers";
+const SUSPICIOUS_PEERS: &str = "suspicious_peers";
+
+/// What PeerSwap uses when the file does not set `min_swap_amount_msat`.
+pub const DEFAULT_MIN_SWAP_AMOUNT: Amount = Amount::from_sat(100_000);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Policy {
+    pub accept_all_peers: bool,
+    pub min_swap_amount_msat: Amount,
+    pub allow_new_swaps: Option<bool>,
+    pub allowlisted_peers: Vec<String>,
+    pub suspicious_peers: Vec<String>,
+    /// Keys this model does not know about, written back untouched.
+    pub other: Vec<(String, String)>,
+    /// `#` comment lines, written back at the top of the file.
+    pub comments: Vec<String>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            accept_all_peers: false,
+            min_swap_amount_msat: DEFAULT_MIN_SWAP_AMOUNT,
+            allow_new_swaps: None,
+            allowlisted_peers: Vec::new(),
+            suspicious_peers: Vec::new(),
+            other: Vec::new(),
+            comments: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut policy = Policy::default();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                policy.comments.push(line.to_string());
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .with_context(|| format!("line {}: expected key=value, got {:?}", n + 1, line))?;
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                ACCEPT_ALL_PEERS => policy.accept_all_peers = value.parse().with_context(|| format!("line {}", n + 1))?,
+                MIN_SWAP_AMOUNT_MSAT => policy.min_swap_amount_msat = Amount::from_msat(value.parse().with_context(|| format!("line {}", n + 1))?),
+                ALLOW_NEW_SWAPS => policy.allow_new_swaps = Some(value.parse().with_context(|| format!("line {}", n + 1))?),
+                ALLOWLISTED_PEERS => policy.allowlisted_peers.push(value.to_string()),
+                SUSPICIOUS_PEERS => policy.suspicious_peers.push(value.to_string()),
+                _ => policy.other.push((key.to_string(), value.to_string())),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for comment in &self.comments {
+            writeln!(f, "{}", comment)?;
+        }
+        writeln!(f, "{}={}", ACCEPT_ALL_PEERS, self.accept_all_peers)?;
+        writeln!(f, "{}={}", MIN_SWAP_AMOUNT_MSAT, self.min_swap_amount_msat.to_msat())?;
+        if let Some(allow) = self.allow_new_swaps {
+            writeln!(f, "{}={}", ALLOW_NEW_SWAPS, allow)?;
+        }
+        for peer in &self.allowlisted_peers {
+            writeln!(f, "{}={}", ALLOWLISTED_PEERS, peer)?;
+        }
+        for peer in &self.suspicious_peers {
+            writeln!(f, "{}={}", SUSPICIOUS_PEERS, peer)?;
+        }
+        for (key, value) in &self.other {
+            writeln!(f, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Policy {
+    pub async fn load(path: &Path) -> Result<Self> {
+        fs::read_to_string(path)
+            .await
+            .with_context(|| format!("reading {}", path.display()))?
+            .parse()
+    }
+
+    /// Writes the policy in place. The file is a single-file bind mount, so it must
+    /// be truncated and rewritten rather than replaced by a rename.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_string()).await.with_context(|| format!("writing {}", path.display()))
+    }
+
+    pub fn add_allowlisted_peer(&mut self, peer_id: NodeId) {
+        if !self.allowlisted_peers.iter().any(|p| *p == peer_id.to_string()) {
+            self.allowlisted_peers.push(peer_id.to_string());
+        }
+    }
+
+    pub fn remove_allowlisted_peer(&mut self, peer_id: NodeId) {
+        self.allowlisted_peers.retain(|p| *p != peer_id.to_string());
+    }
+
+    pub fn add_suspicious_peer(&mut self, peer_id: NodeId) {
+        if !self.suspicious_peers.iter().any(|p| *p == peer_id.to_string()) {
+            self.suspicious_peers.push(peer_id.to_string());
+        }
+    }
+
+    pub fn remove_suspicious_peer(&mut self, peer_id: NodeId) {
+        self.suspicious_peers.retain(|p| *p != peer_id.to_string());
+    }
+}
+
+/// Makes the plugin in `container` re-read `policy.conf`.
+pub async fn reload_policy(container: &str) -> Result<serde_json::Value> {
+    Ok(cli(container, &["peerswap-reloadpolicy"]).await?)
+}
+
+/// Saves `policy` and reloads it on every container that mounts the file.
+pub async fn apply(policy: &Policy, containers: &[&str]) -> Result<()> {
+    policy.save(&policy_path()).await?;
+    for container in containers {
+        reload_policy(container).await?;
+    }
+    Ok(())
+}
+
+/// Checks that `peer` refuses swaps from a non-allowlisted or suspicious
+/// `initiator`, and swaps below `min_swap_amount_msat`.
+/// The original `policy.conf` is restored afterwards.
+pub async fn run_policy_scenarios(initiator: &str, peer: &str, scid: Scid) -> Result<()> {
+    let original = Policy::load(&policy_path()).await?;
+    let containers = [initiator, peer];
+    let result: Result<()> = async {
+        let initiator_id = node_id(initiator).await?;
+
+        let scid = scid.to_string();
+        let amount = SWAP_AMOUNT.to_sat()?.to_string();
+        let mut policy = original.clone();
+        policy.accept_all_peers = false;
+        policy.remove_allowlisted_peer(initiator_id);
+        apply(&policy, &containers).await?;
+        expect_swap_refused(initiator, &["peerswap-swap-out", &scid, &amount, "btc", "10000"], SwapRefusal::PeerNotAllowed).await?;
+        println!("Swap from non-allowlisted peer rejected");
+
+        policy.add_allowlisted_peer(initiator_id);
+        policy.add_suspicious_peer(initiator_id);
+        apply(&policy, &containers).await?;
+        expect_swap_refused(initiator, &["peerswap-swap-out", &scid, &amount, "btc", "10000"], SwapRefusal::PeerNotAllowed).await?;
+        println!("Swap from suspicious peer rejected");
+
+        policy.remove_suspicious_peer(initiator_id);
+        // Raise the minimum above the swap amount instead of sending dust swaps
+        policy.min_swap_amount_msat = SWAP_AMOUNT.checked_add(SWAP_AMOUNT)?;
+        apply(&policy, &containers).await?;
+        anyhow::ensure!(Policy::load(&policy_path()).await? == policy, "policy.conf did not round-trip");
+        expect_swap_refused(initiator, &["peerswap-swap-out", &scid, &amount, "btc", "10000"], SwapRefusal::BelowMinimum).await?;
+        println!("Swap below min_swap_amount_msat rejected");
+        Ok(())
+    }
+    .await;
+
+    apply(&original, &containers).await?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    #[test]
+    fn parses_known_and_unknown_keys() {
+        let policy: Policy = format!(
+            "# swaps from anyone\naccept_all_peers=true\n\nmin_swap_amount_msat = 11000\nallowlisted_peers={}\nfuture_key=1\n",
+            PEER
+        )
+        .parse()
+        .unwrap();
+        assert!(policy.accept_all_peers);
+        assert_eq!(policy.min_swap_amount_msat, Amount::from_msat(11_000));
+        assert_eq!(policy.allow_new_swaps, None);
+        assert_eq!(policy.allowlisted_peers, [PEER]);
+        assert_eq!(policy.other, [("future_key".to_string(), "1".to_string())]);
+        assert_eq!(policy.comments, ["# swaps from anyone"]);
+    }
+
+    #[test]
+    fn defaults_min_swap_amount_like_peerswap() {
+        let policy: Policy = "accept_all_peers=false\n".parse().unwrap();
+        assert_eq!(policy.min_swap_amount_msat, DEFAULT_MIN_SWAP_AMOUNT);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!("accept_all_peers".parse::<Policy>().is_err());
+        assert!("accept_all_peers=maybe".parse::<Policy>().is_err());
+        assert!("min_swap_amount_msat=1.5".parse::<Policy>().is_err());
+    }
+
+    #[test]
+    fn round_trips_with_comments() {
+        let text = "# kept\naccept_all_peers=false\nmin_swap_amount_msat=200000000\nallow_new_swaps=true\nsuspicious_peers=x\nfuture_key=1\n";
+        let policy: Policy = text.parse().unwrap();
+        assert_eq!(policy.to_string(), text);
+        assert_eq!(policy.to_string().parse::<Policy>().unwrap(), policy);
+    }
+
+    #[test]
+    fn edits_peer_lists_once() {
+        let peer: NodeId = PEER.parse().unwrap();
+        let mut policy = Policy::default();
+        policy.add_allowlisted_peer(peer);
+        policy.add_allowlisted_peer(peer);
+        policy.add_suspicious_peer(peer);
+        assert_eq!(policy.allowlisted_peers, [PEER]);
+        assert_eq!(policy.suspicious_peers, [PEER]);
+        policy.remove_allowlisted_peer(peer);
+        policy.remove_suspicious_peer(peer);
+        assert!(policy.allowlisted_peers.is_empty() && policy.suspicious_peers.is_empty());
+    }
+}
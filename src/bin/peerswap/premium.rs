@@ -0,0 +1,237 @@
+//! Premium rate matrix: global and per-peer rates for both assets and both
+//! directions, plus rejection of swaps whose premium exceeds `max_premium_ppm`.
+
+use anyhow::{Context, Result};
+
+use super::bitcoin_rpc::AsyncBitcoinClient as BitcoinClient;
+use super::amount::Amount;
+use super::error::SwapRefusal;
+use super::ids::{NodeId, Scid};
+use super::setup::node_id;
+use super::{metrics, recorder};
+use super::{cli, expect_swap_refused, liquid_generate, neutral_address, set_premium_rate, swap_in, swap_in_lbtc, swap_out, swap_out_lbtc, SwapResult, SWAP_AMOUNT};
+
+pub const ASSETS: [&str; 2] = ["btc", "lbtc"];
+
+// PeerSwap defaults, restored once the matrix is done.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    SwapOut,
+    SwapIn,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 2] = [Direction::SwapOut, Direction::SwapIn];
+
+    pub fn operation(self) -> &'static str {
+        match self {
+            Direction::SwapOut => "swap_out",
+            Direction::SwapIn => "swap_in",
+        }
+    }
+
+    fn command(self) -> &'static str {
+        match self {
+            Direction::SwapOut => "peerswap-swap-out",
+            Direction::SwapIn => "peerswap-swap-in",
+        }
+    }
+}
+
+pub async fn set_peer_premium_rate(container: &str, peer_id: NodeId, asset: &str, direction: Direction, ppm: u64) -> Result<()> {
+    cli(container, &["peerswap-updatepremiumrate", &peer_id.to_string(), asset, direction.operation(), &ppm.to_string()]).await?;
+    Ok(())
+}
+
+pub async fn delete_peer_premium_rate(container: &str, peer_id: NodeId, asset: &str, direction: Direction) -> Result<()> {
+    cli(container, &["peerswap-deletepremiumrate", &peer_id.to_string(), asset, direction.operation()]).await?;
+    Ok(())
+}
+
+/// The global premium rate `container` charges for `asset` and `direction`.
+pub async fn global_premium_rate(container: &str, asset: &str, direction: Direction) -> Result<u64> {
+    cli(container, &["peerswap-getglobalpremiumrate", asset, direction.operation()]).await?["premium_rate_ppm"]
+        .as_u64()
+        .context("No premium_rate_ppm")
+}
+
+/// The premium rate `container` charges `peer_id`, its override or else the global rate.
+pub async fn peer_premium_rate(container: &str, peer_id: NodeId, asset: &str, direction: Direction) -> Result<u64> {
+    cli(container, &["peerswap-getpremiumrate", &peer_id.to_string(), asset, direction.operation()]).await?["premium_rate_ppm"]
+        .as_u64()
+        .context("No premium_rate_ppm")
+}
+
+/// Global and per-peer rates of one asset and direction, as found before a
+/// scenario changed them.
+struct SavedRate {
+    asset: &'static str,
+    direction: Direction,
+    global_ppm: u64,
+    peer_ppm: u64,
+}
+
+async fn save_premium_rates(container: &str, peer_id: NodeId) -> Result<Vec<SavedRate>> {
+    let mut saved = Vec::new();
+    for asset in ASSETS {
+        for direction in Direction::ALL {
+            saved.push(SavedRate {
+                asset,
+                direction,
+                global_ppm: global_premium_rate(container, asset, direction).await?,
+                peer_ppm: peer_premium_rate(container, peer_id, asset, direction).await?,
+            });
+        }
+    }
+    Ok(saved)
+}
+
+/// Puts back the rates `save_premium_rates` found. A peer rate equal to the
+/// global one is taken to have had no override.
+async fn restore_premium_rates(container: &str, peer_id: NodeId, saved: &[SavedRate]) -> Result<()> {
+    for rate in saved {
+        cli(container, &["peerswap-updateglobalpremiumrate", rate.asset, rate.direction.operation(), &rate.global_ppm.to_string()]).await?;
+        if rate.peer_ppm == rate.global_ppm {
+            // Fails when there is no override to delete
+            let _ = delete_peer_premium_rate(container, peer_id, rate.asset, rate.direction).await;
+        } else {
+            set_peer_premium_rate(container, peer_id, rate.asset, rate.direction, rate.peer_ppm).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Premium PeerSwap quotes for `amount` at `ppm`, in sats rounded down like the plugin does.
+pub fn expected_premium(amount: Amount, ppm: u64) -> i64 {
+    (amount.to_sat_floor() as u128 * ppm as u128 / 1_000_000) as i64
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn run_swap(
+    btc: &BitcoinClient,
+    container: &str,
+    scid: Scid,
+    asset: &str,
+    direction: Direction,
+    amount: Amount,
+    max_premium_ppm: u64,
+    mine_to: &bitcoincore_rpc::bitcoin::Address,
+) -> Result<SwapResult> {
+    let started = std::time::Instant::now();
+    let result = match (asset, direction) {
+        ("btc", Direction::SwapOut) => swap_out(btc, container, scid, amount, max_premium_ppm, mine_to).await,
+        ("btc", Direction::SwapIn) => swap_in(btc, container, scid, amount, max_premium_ppm, mine_to).await,
+        ("lbtc", Direction::SwapOut) => swap_out_lbtc(container, scid, amount, max_premium_ppm).await,
+        ("lbtc", Direction::SwapIn) => swap_in_lbtc(container, scid, amount, max_premium_ppm).await,
+        _ => anyhow::bail!("Unknown asset: {}", asset),
+    };
+    let completed = match &result {
+        Ok(r) => Some((r.premium, r.onchain_fee.to_sat_floor())),
+        Err(_) => None,
+    };
+    metrics::record_swap(asset, direction.operation(), started.elapsed(), completed);
+    result
+}
+
+/// Confirmed on-chain balance in sats for `asset`, as seen by the node in `container`.
+pub async fn onchain_balance(container: &str, asset: &str) -> Result<Amount> {
+    match asset {
+        "btc" => Ok(cli(container, &["listfunds"]).await?["outputs"]
+            .as_array()
+            .context("No outputs")?
+            .iter()
+            .filter(|o| o["status"].as_str() == Some("confirmed"))
+            .filter_map(|o| o["amount_msat"].as_u64())
+            .map(Amount::from_msat)
+            .sum::<Result<Amount, _>>()?),
+        "lbtc" => cli(container, &["peerswap-lbtc-getbalance"]).await?["lbtc_balance_sat"]
+            .as_u64()
+            .map(Amount::from_sat)
+            .context("No lbtc_balance_sat"),
+        _ => anyhow::bail!("Unknown asset: {}", asset),
+    }
+}
+
+pub async fn mine(btc: &BitcoinClient, asset: &str, mine_to: &bitcoincore_rpc::bitcoin::Address) -> Result<()> {
+    match asset {
+        "btc" => {
+            btc.generate_to_address(1, mine_to).await?;
+        }
+        _ => liquid_generate(1).await?,
+    }
+    Ok(())
+}
+
+/// Starts a swap that must be refused for its premium, and checks that neither
+/// side moved any on-chain funds.
+#[allow(clippy::too_many_arguments)]
+pub async fn expect_premium_rejection(
+    btc: &BitcoinClient,
+    initiator: &str,
+    peer: &str,
+    scid: Scid,
+    asset: &str,
+    direction: Direction,
+    max_premium_ppm: u64,
+) -> Result<()> {
+    let initiator_before = onchain_balance(initiator, asset).await?;
+    let peer_before = onchain_balance(peer, asset).await?;
+
+    let args = [direction.command(), &scid.to_string(), &SWAP_AMOUNT.to_sat()?.to_string(), asset, &max_premium_ppm.to_string()];
+    expect_swap_refused(initiator, &args, SwapRefusal::PremiumTooHigh).await?;
+
+    // Give a stray opening transaction the chance to confirm before comparing,
+    // without a coinbase of the initiator's maturing into its balance
+    mine(btc, asset, &neutral_address()).await?;
+    recorder::sleep(std::time::Duration::from_secs(2)).await;
+    anyhow::ensure!(onchain_balance(initiator, asset).await? == initiator_before, "{} on-chain {} balance moved", initiator, asset);
+    anyhow::ensure!(onchain_balance(peer, asset).await? == peer_before, "{} on-chain {} balance moved", peer, asset);
+    Ok(())
+}
+
+/// Runs every asset/direction combination against a global rate and a per-peer
+/// override set on `peer`, then checks rejections above `max_premium_ppm`.
+/// The rates `peer` had before are restored afterwards.
+pub async fn run_matrix(
+    btc: &BitcoinClient,
+    initiator: &str,
+    peer: &str,
+    scid: Scid,
+    mine_to: &bitcoincore_rpc::bitcoin::Address,
+) -> Result<()> {
+    let initiator_id = node_id(initiator).await?;
+    let saved = save_premium_rates(peer, initiator_id).await?;
+    let result: Result<()> = async {
+        for asset in ASSETS {
+            set_premium_rate(peer, asset, 3000, 1000).await?;
+            for direction in Direction::ALL {
+                let global_ppm = if direction == Direction::SwapOut { 3000 } else { 1000 };
+                let result = run_swap(btc, initiator, scid, asset, direction, SWAP_AMOUNT, 10_000, mine_to).await?;
+                println!("{} {} global rate {}ppm: premium={}", asset, direction.operation(), global_ppm, result.premium);
+                anyhow::ensure!(
+                    result.premium == expected_premium(SWAP_AMOUNT, global_ppm),
+                    "{} {} premium {} does not match global rate {}ppm", asset, direction.operation(), result.premium, global_ppm
+                );
+
+                let peer_ppm = global_ppm + 2000;
+                set_peer_premium_rate(peer, initiator_id, asset, direction, peer_ppm).await?;
+                let result = run_swap(btc, initiator, scid, asset, direction, SWAP_AMOUNT, 10_000, mine_to).await?;
+                println!("{} {} peer rate {}ppm: premium={}", asset, direction.operation(), peer_ppm, result.premium);
+                anyhow::ensure!(
+                    result.premium == expected_premium(SWAP_AMOUNT, peer_ppm),
+                    "{} {} premium {} does not match peer rate {}ppm", asset, direction.operation(), result.premium, peer_ppm
+                );
+
+                expect_premium_rejection(btc, initiator, peer, scid, asset, direction, peer_ppm - 1).await?;
+                println!("{} {} rejected with max_premium_ppm={}", asset, direction.operation(), peer_ppm - 1);
+                delete_peer_premium_rate(peer, initiator_id, asset, direction).await?;
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    restore_premium_rates(peer, initiator_id, &saved).await?;
+    result
+}
@@ -0,0 +1,347 @@
+//! Runs bitcoind, elementsd and lightningd with the PeerSwap plugin as child
+//! processes instead of docker containers. Every daemon gets its own temporary
+//! directory and free ports, and its output is tailed like pyln's
+//! `TailableProc`, so scenarios can wait for log lines.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+
+use super::regtest_env::{free_port, new_rpc_password, peerswap_conf, write_private, write_rpc_credentials, NODES, RPC_USER};
+
+/// A child process whose stdout and stderr are appended to `<dir>/log` and
+/// kept in memory for `wait_for_log`.
+pub struct TailableProc {
+    name: String,
+    dir: PathBuf,
+    cmd_line: Vec<String>,
+    verbose: bool,
+    child: Option<Child>,
+    lines: Arc<Mutex<Vec<String>>>,
+    logsearch_start: usize,
+}
+
+impl TailableProc {
+    pub fn new(name: &str, dir: &Path, cmd_line: Vec<String>) -> Self {
+        TailableProc {
+            name: name.to_string(),
+            dir: dir.to_path_buf(),
+            cmd_line,
+            verbose: std::env::var_os("PEERSWAP_VERBOSE").is_some(),
+            child: None,
+            lines: Arc::default(),
+            logsearch_start: 0,
+        }
+    }
+
+    pub async fn start(&mut self) -> Result<()> {
+        let mut child = Command::new(&self.cmd_line[0])
+            .args(&self.cmd_line[1..])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("starting {}", self.cmd_line[0]))?;
+        let log = tokio::fs::OpenOptions::new().create(true).append(true).open(self.dir.join("log")).await?;
+        let log = Arc::new(tokio::sync::Mutex::new(log));
+        self.tail(child.stdout.take().context("No stdout")?, log.clone());
+        self.tail(child.stderr.take().context("No stderr")?, log);
+        self.child = Some(child);
+        Ok(())
+    }
+
+    fn tail<R: AsyncRead + Unpin + Send + 'static>(&self, stream: R, log: Arc<tokio::sync::Mutex<tokio::fs::File>>) {
+        let (name, lines, verbose) = (self.name.clone(), self.lines.clone(), self.verbose);
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(stream).lines();
+            while let Ok(Some(line)) = reader.next_line().await {
+                if verbose {
+                    println!("{}: {}", name, line);
+                }
+                let _ = log.lock().await.write_all(format!("{}\n", line).as_bytes()).await;
+                lines.lock().unwrap().push(line);
+            }
+        });
+    }
+
+    /// Waits for a line containing `pattern` that appeared after the previous
+    /// match, and returns it.
+    pub async fn wait_for_log(&mut self, pattern: &str, timeout: Duration) -> Result<String> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            {
+                let lines = self.lines.lock().unwrap();
+                if let Some(i) = lines[self.logsearch_start..].iter().position(|l| l.contains(pattern)) {
+                    self.logsearch_start += i + 1;
+                    return Ok(lines[self.logsearch_start - 1].clone());
+                }
+            }
+            if let Some(status) = self.child.as_mut().and_then(|c| c.try_wait().ok().flatten()) {
+                anyhow::bail!("{} exited with {} before logging '{}'", self.name, status, pattern);
+            }
+            anyhow::ensure!(tokio::time::Instant::now() < deadline, "Timeout waiting for '{}' in {} log", pattern, self.name);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.child.as_ref().and_then(Child::id)
+    }
+
+    pub async fn signal(&self, signal: &str) -> Result<()> {
+        let pid = self.pid().with_context(|| format!("{} is not running", self.name))?;
+        let status = Command::new("kill").args([&format!("-{}", signal), &pid.to_string()]).status().await?;
+        anyhow::ensure!(status.success(), "kill -{} {} failed", signal, self.name);
+        Ok(())
+    }
+
+    /// Asks the process to exit, killing it if it has not after ten seconds.
+    pub async fn stop(&mut self) -> Result<()> {
+        let Some(mut child) = self.child.take() else { return Ok(()) };
+        if let Some(pid) = child.id() {
+            let _ = Command::new("kill").args(["-TERM", &pid.to_string()]).status().await;
+        }
+        if tokio::time::timeout(Duration::from_secs(10), child.wait()).await.is_err() {
+            child.kill().await?;
+        }
+        Ok(())
+    }
+
+    /// Sends SIGKILL without waiting, for when there is no time to `stop`.
+    fn kill_now(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.start_kill();
+        }
+    }
+}
+
+struct LocalNode {
+    dir: PathBuf,
+    port: u16,
+}
+
+/// The daemons of a process-based stack.
+pub struct ProcessEnv {
+    data_dir: PathBuf,
+    bitcoind_rpc_port: u16,
+    elementsd_rpc_port: u16,
+    nodes: HashMap<&'static str, LocalNode>,
+    procs: tokio::sync::Mutex<HashMap<String, TailableProc>>,
+}
+
+static ACTIVE: OnceLock<ProcessEnv> = OnceLock::new();
+
+/// Owns the running stack for `main`. The stack itself lives in a static so
+/// the helpers can find it, and statics are never dropped, so the children's
+/// `kill_on_drop` would never fire; dropping the guard kills them instead,
+/// also when a scenario panics or returns early before `down`.
+pub struct ProcessEnvGuard(&'static ProcessEnv);
+
+impl std::ops::Deref for ProcessEnvGuard {
+    type Target = ProcessEnv;
+
+    fn deref(&self) -> &ProcessEnv {
+        self.0
+    }
+}
+
+impl Drop for ProcessEnvGuard {
+    fn drop(&mut self) {
+        // Only `down` and the scenario helpers hold the lock, and neither runs
+        // once `main` lets go of the guard
+        if let Ok(mut procs) = self.0.procs.try_lock() {
+            procs.values_mut().for_each(TailableProc::kill_now);
+        }
+    }
+}
+
+/// The running process-based stack, if the helpers should talk to it instead
+/// of to docker containers.
+pub fn active() -> Option<&'static ProcessEnv> {
+    ACTIVE.get()
+}
+
+fn peerswap_plugin() -> Result<PathBuf> {
+    if let Some(path) = std::env::var_os("PEERSWAP_PLUGIN") {
+        return Ok(PathBuf::from(path));
+    }
+    std::env::split_paths(&std::env::var_os("PATH").unwrap_or_default())
+        .map(|dir| dir.join("peerswap"))
+        .find(|p| p.is_file())
+        .context("peerswap plugin not found; set PEERSWAP_PLUGIN")
+}
+
+impl ProcessEnv {
+    /// Starts bitcoind, elementsd and one lightningd per node under a new
+    /// temporary directory. Only one stack can be active per process, and it
+    /// is killed when the returned guard is dropped.
+    pub async fn start() -> Result<ProcessEnvGuard> {
+        let data_dir = std::env::temp_dir().join(format!("peerswap-local-{}-{:08x}", std::process::id(), rand::random::<u32>()));
+        let plugin = peerswap_plugin()?;
+        let mut nodes = HashMap::new();
+        for node in NODES {
+            nodes.insert(node, LocalNode { dir: data_dir.join(node), port: free_port()? });
+        }
+        let env = ProcessEnv {
+            bitcoind_rpc_port: free_port()?,
+            elementsd_rpc_port: free_port()?,
+            nodes,
+            procs: Default::default(),
+            data_dir,
+        };
+        ACTIVE.set(env).map_err(|_| anyhow::anyhow!("A local regtest stack is already running"))?;
+        // The guard exists before any daemon does, so a failed start kills
+        // what it launched and removes the data directory
+        let guard = ProcessEnvGuard(ACTIVE.get().expect("just set"));
+        if let Err(e) = guard.launch_all(&plugin).await {
+            let _ = guard.down(false).await;
+            return Err(e);
+        }
+        Ok(guard)
+    }
+
+    async fn launch_all(&self, plugin: &Path) -> Result<()> {
+        std::fs::create_dir_all(&self.data_dir)?;
+        new_rpc_password()?;
+        println!("Starting local regtest daemons in {}", self.data_dir.display());
+
+        let bitcoind_dir = self.data_dir.join("bitcoind");
+        std::fs::create_dir_all(&bitcoind_dir)?;
+        write_rpc_credentials(&bitcoind_dir.join("bitcoin.conf"), "")?;
+        let bitcoind = TailableProc::new("bitcoind", &bitcoind_dir, vec![
+            "bitcoind".into(),
+            "-regtest".into(),
+            "-server".into(),
+            "-printtoconsole".into(),
+            format!("-datadir={}", bitcoind_dir.display()),
+            format!("-port={}", free_port()?),
+            format!("-rpcport={}", self.bitcoind_rpc_port),
+            "-prune=550".into(),
+            "-disablewallet=0".into(),
+        ]);
+        self.launch(bitcoind, "init message: Done loading").await?;
+
+        let elementsd_dir = self.data_dir.join("elementsd");
+        std::fs::create_dir_all(&elementsd_dir)?;
+        write_rpc_credentials(&elementsd_dir.join("elements.conf"), "")?;
+        let elementsd = TailableProc::new("elementsd", &elementsd_dir, vec![
+            "elementsd".into(),
+            "-chain=liquidregtest".into(),
+            "-server".into(),
+            "-printtoconsole".into(),
+            format!("-datadir={}", elementsd_dir.display()),
+            format!("-port={}", free_port()?),
+            format!("-rpcport={}", self.elementsd_rpc_port),
+            "-validatepegin=0".into(),
+            "-initialfreecoins=2100000000000000".into(),
+            "-acceptdiscountct=1".into(),
+            "-anyonecanspendaremine=1".into(),
+        ]);
+        self.launch(elementsd, "init message: Done loading").await?;
+
+        // Both nodes share one policy.conf, as the containers share one bind mount
+        std::fs::copy("policy.conf", self.policy_path()).context("copying policy.conf")?;
+        let peerswap_conf = peerswap_conf()?
+            .replace("http://bitcoind", "http://127.0.0.1")
+            .replace("http://elementsd", "http://127.0.0.1")
+            .replace("rpcport=18443", &format!("rpcport={}", self.bitcoind_rpc_port))
+            .replace("rpcport=7041", &format!("rpcport={}", self.elementsd_rpc_port));
+        for name in NODES {
+            let node = &self.nodes[name];
+            let peerswap_dir = node.dir.join("regtest").join("peerswap");
+            std::fs::create_dir_all(&peerswap_dir)?;
+            write_private(&peerswap_dir.join("peerswap.conf"), &peerswap_conf)?;
+            std::os::unix::fs::symlink(self.policy_path(), peerswap_dir.join("policy.conf"))?;
+            write_rpc_credentials(&node.dir.join("config"), "bitcoin-")?;
+            let lightningd = TailableProc::new(name, &node.dir, vec![
+                "lightningd".into(),
+                format!("--lightning-dir={}", node.dir.display()),
+                "--network=regtest".into(),
+                "--bitcoin-rpcconnect=127.0.0.1".into(),
+                format!("--bitcoin-rpcport={}", self.bitcoind_rpc_port),
+                format!("--plugin={}", plugin.display()),
+                format!("--addr=127.0.0.1:{}", node.port),
+                format!("--grpc-port={}", free_port()?),
+            ]);
+            self.launch(lightningd, "Server started with public key").await?;
+        }
+
+        Ok(())
+    }
+
+    async fn launch(&self, mut proc: TailableProc, ready: &str) -> Result<()> {
+        proc.start().await?;
+        proc.wait_for_log(ready, Duration::from_secs(60)).await?;
+        self.procs.lock().await.insert(proc.name.clone(), proc);
+        Ok(())
+    }
+
+    pub fn bitcoind_url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.bitcoind_rpc_port)
+    }
+
+    pub fn policy_path(&self) -> PathBuf {
+        self.data_dir.join("policy.conf")
+    }
+
+    fn node(&self, node: &str) -> Result<&LocalNode> {
+        self.nodes.get(node).with_context(|| format!("No local node {}", node))
+    }
+
+    pub fn lightning_cli(&self, node: &str) -> Result<Command> {
+        let mut cmd = Command::new("lightning-cli");
+        cmd.arg(format!("--lightning-dir={}", self.node(node)?.dir.display())).arg("--network=regtest");
+        Ok(cmd)
+    }
+
+    pub fn elements_cli(&self) -> Command {
+        let mut cmd = Command::new("elements-cli");
+        cmd.args(["-chain=liquidregtest", &format!("-rpcuser={}", RPC_USER), "-stdinrpcpass", &format!("-rpcport={}", self.elementsd_rpc_port)]);
+        cmd
+    }
+
+    /// `host:port` other nodes connect to `node` on.
+    pub fn node_addr(&self, node: &str) -> Result<String> {
+        Ok(format!("127.0.0.1:{}", self.node(node)?.port))
+    }
+
+    /// Stops `name` until `restart`, like `docker stop`.
+    pub async fn stop(&self, name: &str) -> Result<()> {
+        self.procs.lock().await.get_mut(name).with_context(|| format!("No process {}", name))?.stop().await
+    }
+
+    pub async fn restart(&self, name: &str) -> Result<()> {
+        let mut procs = self.procs.lock().await;
+        let proc = procs.get_mut(name).with_context(|| format!("No process {}", name))?;
+        proc.start().await?;
+        proc.wait_for_log("Server started with public key", Duration::from_secs(60)).await?;
+        Ok(())
+    }
+
+    /// Sends `signal` to `name`, e.g. `STOP`/`CONT` to freeze it like `docker pause`.
+    pub async fn signal(&self, name: &str, signal: &str) -> Result<()> {
+        self.procs.lock().await.get(name).with_context(|| format!("No process {}", name))?.signal(signal).await
+    }
+
+    /// Stops every daemon, lightningd first. Without `keep_logs` the data
+    /// directory is removed.
+    pub async fn down(&self, keep_logs: bool) -> Result<()> {
+        let mut procs = self.procs.lock().await;
+        for name in NODES.into_iter().chain(["elementsd", "bitcoind"]) {
+            if let Some(proc) = procs.get_mut(name) {
+                proc.stop().await?;
+            }
+        }
+        if keep_logs {
+            println!("Kept logs of the local daemons in {}", self.data_dir.display());
+        } else {
+            std::fs::remove_dir_all(&self.data_dir)?;
+        }
+        Ok(())
+    }
+}
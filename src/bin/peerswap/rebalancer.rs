@@ -0,0 +1,319 @@
+//! Channel rebalancer: polls channel balances, and swaps channels that drift
+//! outside their target ratio back towards it, within premium and fee budgets.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::bitcoin_rpc::AsyncBitcoinClient as BitcoinClient;
+use super::amount::Amount;
+use super::ids::{NodeId, Scid};
+use super::discovery::{channel_balances, list_peers, ChannelBalance, PeerSwapPeer};
+use super::premium::{run_swap, Direction, ASSETS};
+use super::recorder;
+
+// Rough opening transaction sizes, used only to compare assets
+const BTC_OPENING_VBYTES: u64 = 200;
+const LBTC_OPENING_VBYTES: u64 = 2_600;
+const LBTC_FEERATE_SAT_PER_KVB: u64 = 100;
+
+pub struct RebalancerConfig {
+    /// Local share of the channel capacity to aim for, in parts per million.
+    pub target_ratio_ppm: u64,
+    /// Distance from the target, in parts per million, tolerated before swapping.
+    pub hysteresis_ppm: u64,
+    pub min_swap: Amount,
+    pub max_swap: Amount,
+    /// Premiums plus on-chain fees the rebalancer may spend per peer.
+    pub peer_budget: Amount,
+    /// Premiums plus on-chain fees the rebalancer may spend in total.
+    pub global_budget: Amount,
+    pub max_premium_ppm: u64,
+    /// Used when `estimatesmartfee` has no estimate, as on a fresh regtest chain.
+    pub btc_fallback_feerate_sat_per_kvb: u64,
+    pub poll_interval: Duration,
+    /// Log decisions without running any swap.
+    pub dry_run: bool,
+}
+
+impl Default for RebalancerConfig {
+    fn default() -> Self {
+        RebalancerConfig {
+            target_ratio_ppm: 500_000,
+            hysteresis_ppm: 100_000,
+            min_swap: Amount::from_sat(100_000),
+            max_swap: Amount::from_sat(1_000_000),
+            peer_budget: Amount::from_sat(10_000),
+            global_budget: Amount::from_sat(50_000),
+            max_premium_ppm: 10_000,
+            btc_fallback_feerate_sat_per_kvb: 1_000,
+            poll_interval: Duration::from_secs(30),
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Decision {
+    pub scid: Scid,
+    pub peer_id: NodeId,
+    pub direction: Direction,
+    pub asset: &'static str,
+    pub amount: Amount,
+    pub estimated_cost: Amount,
+}
+
+async fn opening_fee(btc: &BitcoinClient, asset: &str, config: &RebalancerConfig) -> Amount {
+    match asset {
+        "btc" => {
+            let feerate = btc
+                .estimate_smart_fee(6).await
+                .ok()
+                .and_then(|e| e.fee_rate)
+                .map(|r| r.to_sat())
+                .unwrap_or(config.btc_fallback_feerate_sat_per_kvb);
+            Amount::from_sat(feerate * BTC_OPENING_VBYTES / 1000)
+        }
+        _ => Amount::from_sat(LBTC_FEERATE_SAT_PER_KVB * LBTC_OPENING_VBYTES / 1000),
+    }
+}
+
+/// Premiums plus on-chain fees spent so far, in total and per peer.
+#[derive(Clone, Default)]
+struct Spending {
+    total: Amount,
+    per_peer: HashMap<NodeId, Amount>,
+}
+
+impl Spending {
+    fn peer(&self, peer_id: NodeId) -> Amount {
+        self.per_peer.get(&peer_id).copied().unwrap_or_default()
+    }
+
+    fn add(&mut self, peer_id: NodeId, cost: Amount) -> Result<()> {
+        self.per_peer.insert(peer_id, self.peer(peer_id).checked_add(cost)?);
+        self.total = self.total.checked_add(cost)?;
+        Ok(())
+    }
+}
+
+/// Picks the channels outside the hysteresis band around the target and sizes
+/// a swap back to the target for each, cheapest asset first, skipping any that
+/// would take `spent` over a budget.
+fn plan_swaps(
+    cfg: &RebalancerConfig,
+    spent: &Spending,
+    channels: &[ChannelBalance],
+    peers: &HashMap<NodeId, PeerSwapPeer>,
+    opening_fees: &HashMap<&str, Amount>,
+) -> Result<Vec<Decision>> {
+    let mut decisions = Vec::new();
+    let mut planned = spent.clone();
+
+    for ch in channels {
+        let ratio = ch.ratio_ppm();
+        let target = Amount::from_msat((ch.capacity.to_msat().0 as u128 * cfg.target_ratio_ppm as u128 / 1_000_000) as u64);
+        let (direction, gap) = if ratio > cfg.target_ratio_ppm + cfg.hysteresis_ppm {
+            (Direction::SwapOut, ch.local.checked_sub(target)?)
+        } else if ratio + cfg.hysteresis_ppm < cfg.target_ratio_ppm {
+            (Direction::SwapIn, target.checked_sub(ch.local)?)
+        } else {
+            continue;
+        };
+        let amount = Amount::checked_from_sat(gap.min(cfg.max_swap).to_sat_floor())?;
+        if amount < cfg.min_swap {
+            tracing::info!(scid = %ch.scid, %amount, min_swap = %cfg.min_swap, "Off target but below min_swap");
+            continue;
+        }
+
+        let Some(peer) = peers.get(&ch.peer_id) else {
+            tracing::info!(scid = %ch.scid, peer_id = %ch.peer_id, "Peer does not speak PeerSwap");
+            continue;
+        };
+        let best = peer
+            .supported_assets
+            .iter()
+            .filter_map(|&asset| {
+                let ppm = peer.premium_ppm(asset, direction)?;
+                (ppm <= cfg.max_premium_ppm).then_some((asset, ppm))
+            })
+            .map(|(asset, ppm)| {
+                let premium = Amount::checked_from_sat(amount.to_sat_floor() * ppm / 1_000_000)?;
+                Ok((asset, premium.checked_add(opening_fees[asset])?))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .min_by_key(|(_, cost)| *cost);
+        let Some((asset, cost)) = best else {
+            tracing::info!(scid = %ch.scid, max_premium_ppm = cfg.max_premium_ppm, "No asset within max_premium_ppm");
+            continue;
+        };
+
+        if planned.peer(ch.peer_id).checked_add(cost)? > cfg.peer_budget {
+            tracing::info!(scid = %ch.scid, asset, %cost, peer_budget = %cfg.peer_budget, "Skipped: peer budget exhausted");
+            continue;
+        }
+        if planned.total.checked_add(cost)? > cfg.global_budget {
+            tracing::info!(scid = %ch.scid, asset, %cost, global_budget = %cfg.global_budget, "Skipped: global budget exhausted");
+            continue;
+        }
+        planned.add(ch.peer_id, cost)?;
+        decisions.push(Decision {
+            scid: ch.scid,
+            peer_id: ch.peer_id,
+            direction,
+            asset,
+            amount,
+            estimated_cost: cost,
+        });
+    }
+    Ok(decisions)
+}
+
+pub struct Rebalancer<'a> {
+    btc: &'a BitcoinClient,
+    container: String,
+    config: RebalancerConfig,
+    spent: Spending,
+}
+
+impl<'a> Rebalancer<'a> {
+    pub fn new(btc: &'a BitcoinClient, container: &str, config: RebalancerConfig) -> Self {
+        Rebalancer {
+            btc,
+            container: container.to_string(),
+            config,
+            spent: Spending::default(),
+        }
+    }
+
+    /// Works out which channels need a swap, cheapest asset first, within budget.
+    pub async fn plan(&self) -> Result<Vec<Decision>> {
+        let peers: HashMap<_, _> = list_peers(&self.container).await?
+            .into_iter()
+            .filter(|p| p.swaps_allowed)
+            .map(|p| (p.node_id, p))
+            .collect();
+        let mut opening_fees = HashMap::new();
+        for asset in ASSETS {
+            opening_fees.insert(asset, opening_fee(self.btc, asset, &self.config).await);
+        }
+        let channels = channel_balances(&self.container).await?;
+        plan_swaps(&self.config, &self.spent, &channels, &peers, &opening_fees)
+    }
+
+    /// Plans and, unless in dry-run mode, runs the swaps once. A failed swap
+    /// is logged and does not keep the rest of the plan from running.
+    #[tracing::instrument(name = "rebalancer", skip_all, fields(node = %self.container))]
+    pub async fn tick(&mut self, mine_to: &bitcoincore_rpc::bitcoin::Address) -> Result<()> {
+        for d in self.plan().await? {
+            tracing::info!(
+                scid = %d.scid,
+                operation = d.direction.operation(),
+                asset = d.asset,
+                amount = %d.amount,
+                cost = %d.estimated_cost,
+                dry_run = self.config.dry_run,
+                "Planned swap"
+            );
+            if self.config.dry_run {
+                continue;
+            }
+            let result = match run_swap(self.btc, &self.container, d.scid, d.asset, d.direction, d.amount, self.config.max_premium_ppm, mine_to).await {
+                Ok(result) => result,
+                Err(e) => {
+                    tracing::warn!(scid = %d.scid, operation = d.direction.operation(), asset = d.asset, error = ?e, "Swap failed");
+                    continue;
+                }
+            };
+            let cost = Amount::from_sat(result.premium.max(0) as u64).checked_add(result.onchain_fee)?;
+            tracing::info!(scid = %d.scid, asset = d.asset, premium = result.premium, onchain_fee = %result.onchain_fee, %cost, "Swap done");
+            self.spent.add(d.peer_id, cost)?;
+        }
+        Ok(())
+    }
+
+    /// Polls forever, or for `rounds` rounds when given.
+    pub async fn run(&mut self, mine_to: &bitcoincore_rpc::bitcoin::Address, rounds: Option<usize>) -> Result<()> {
+        let mut round = 0;
+        while rounds.is_none_or(|r| round < r) {
+            if let Err(e) = self.tick(mine_to).await {
+                tracing::warn!(round, error = ?e, "Rebalancer round failed");
+            }
+            round += 1;
+            recorder::sleep(self.config.poll_interval).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The secp256k1 generator and its double
+    const ALICE: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const BOB: &str = "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5";
+
+    fn channel(scid: &str, peer: &str, local_sat: u64) -> ChannelBalance {
+        ChannelBalance {
+            scid: scid.parse().unwrap(),
+            peer_id: peer.parse().unwrap(),
+            local: Amount::from_sat(local_sat),
+            capacity: Amount::from_sat(1_000_000),
+        }
+    }
+
+    /// Peers charging 1000ppm on BTC, so a 300k sat swap costs 300 sat plus the opening fee.
+    fn peers() -> HashMap<NodeId, PeerSwapPeer> {
+        [ALICE, BOB]
+            .into_iter()
+            .map(|id| {
+                let node_id: NodeId = id.parse().unwrap();
+                let premiums = [(("btc", "swap_out"), 1000), (("btc", "swap_in"), 1000)].into_iter().collect();
+                (node_id, PeerSwapPeer { node_id, swaps_allowed: true, supported_assets: vec!["btc"], premiums })
+            })
+            .collect()
+    }
+
+    fn plan(cfg: &RebalancerConfig, spent: &Spending, channels: &[ChannelBalance]) -> Vec<Decision> {
+        let opening_fees = [("btc", Amount::from_sat(200))].into_iter().collect();
+        plan_swaps(cfg, spent, channels, &peers(), &opening_fees).unwrap()
+    }
+
+    #[test]
+    fn leaves_channels_inside_the_band_alone() {
+        // Target 50% with a 10% band: 40% to 60% local is left as it is
+        let channels = [channel("1x1x0", ALICE, 550_000), channel("1x2x0", ALICE, 400_000)];
+        assert!(plan(&RebalancerConfig::default(), &Spending::default(), &channels).is_empty());
+    }
+
+    #[test]
+    fn swaps_channels_outside_the_band_back_to_the_target() {
+        let channels = [channel("1x1x0", ALICE, 800_000), channel("1x2x0", BOB, 250_000)];
+        let decisions = plan(&RebalancerConfig::default(), &Spending::default(), &channels);
+        assert_eq!(decisions.len(), 2);
+        assert_eq!(decisions[0].direction, Direction::SwapOut);
+        assert_eq!(decisions[0].amount, Amount::from_sat(300_000));
+        assert_eq!(decisions[0].estimated_cost, Amount::from_sat(500));
+        assert_eq!(decisions[1].direction, Direction::SwapIn);
+        assert_eq!(decisions[1].amount, Amount::from_sat(250_000));
+        assert_eq!(decisions[1].estimated_cost, Amount::from_sat(450));
+    }
+
+    #[test]
+    fn budgets_cap_the_plan() {
+        let channels = [channel("1x1x0", ALICE, 800_000), channel("1x2x0", ALICE, 800_000), channel("1x3x0", BOB, 800_000)];
+        let cfg = RebalancerConfig { peer_budget: Amount::from_sat(800), ..RebalancerConfig::default() };
+        // The second channel to Alice would take her over the peer budget
+        let decisions = plan(&cfg, &Spending::default(), &channels);
+        let scids: Vec<_> = decisions.iter().map(|d| d.scid.to_string()).collect();
+        assert_eq!(scids, ["1x1x0", "1x3x0"]);
+
+        // Earlier swaps count against the global budget
+        let mut spent = Spending::default();
+        spent.add(BOB.parse().unwrap(), Amount::from_sat(49_600)).unwrap();
+        let decisions = plan(&RebalancerConfig::default(), &spent, &channels);
+        assert!(decisions.is_empty());
+    }
+}
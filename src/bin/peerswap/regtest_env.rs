@@ -0,0 +1,325 @@
+//! Runs the docker regtest stack under a unique compose project with fresh
+//! per-run data directories, so scenarios never inherit chain or channel state
+//! from an earlier run.
+
+use anyhow::{Context, Result};
+use std::net::TcpListener;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::process::Command;
+
+use bitcoincore_rpc::Auth;
+
+use super::bitcoin_rpc::AsyncBitcoinClient as BitcoinClient;
+use super::process_env;
+use super::{cli, elements_cli};
+
+const COMPOSE_FILE: &str = "docker-compose.lightningd.yml";
+
+/// Lightning nodes defined in `COMPOSE_FILE`.
+pub const NODES: [&str; 3] = ["alice", "bob", "carol"];
+
+struct Active {
+    container_prefix: String,
+    config_dir: PathBuf,
+}
+
+static ACTIVE: OnceLock<Active> = OnceLock::new();
+
+/// Container running `service`. Outside a managed stack this is the fixed
+/// `container_name` from the compose file.
+pub fn container(service: &str) -> String {
+    let prefix = ACTIVE.get().map(|a| a.container_prefix.as_str()).unwrap_or("");
+    format!("{}{}", prefix, service)
+}
+
+/// The `policy.conf` the nodes of the current stack have mounted.
+pub fn policy_path() -> PathBuf {
+    if let Some(local) = process_env::active() {
+        return local.policy_path();
+    }
+    ACTIVE.get().map(|a| a.config_dir.as_path()).unwrap_or(Path::new(".")).join("policy.conf")
+}
+
+/// `lightning-cli` for `node`, which the caller adds the RPC arguments to.
+pub fn lightning_cli_command(node: &str) -> Result<Command> {
+    if let Some(local) = process_env::active() {
+        return local.lightning_cli(node);
+    }
+    let mut cmd = Command::new("docker");
+    cmd.args(["exec", &container(node), "lightning-cli", "--network=regtest"]);
+    Ok(cmd)
+}
+
+/// RPC user of bitcoind and elementsd in every stack.
+pub const RPC_USER: &str = "user";
+/// RPC credentials of bitcoind and elementsd, which both daemons read with
+/// `-conf` so the password stays out of their command lines.
+const RPC_CONF: &str = "rpc.conf";
+/// lightningd's bitcoind credentials, read with `--conf`.
+const LIGHTNINGD_CONF: &str = "lightningd.conf";
+/// `peerswap.conf` without a password, which `write_stack_config` fills in.
+const PEERSWAP_CONF_TEMPLATE: &str = "peerswap.conf.template";
+
+static RPC_PASSWORD: OnceLock<String> = OnceLock::new();
+
+/// Picks a random RPC password for a stack this process is about to start.
+pub fn new_rpc_password() -> Result<&'static str> {
+    RPC_PASSWORD
+        .set(format!("{:032x}", rand::random::<u128>()))
+        .map_err(|_| anyhow::anyhow!("An RPC password was already chosen"))?;
+    rpc_password()
+}
+
+/// The RPC password of the current stack: the one picked for a stack this
+/// process started, or else the one in the `rpc.conf` that `init-config` wrote
+/// for the long-lived stack.
+pub fn rpc_password() -> Result<&'static str> {
+    if let Some(password) = RPC_PASSWORD.get() {
+        return Ok(password);
+    }
+    let conf = std::fs::read_to_string(RPC_CONF).with_context(|| format!("reading {}; run `peerswap init-config` first", RPC_CONF))?;
+    let password = conf
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("rpcpassword="))
+        .with_context(|| format!("No rpcpassword in {}", RPC_CONF))?;
+    Ok(RPC_PASSWORD.get_or_init(|| password.to_string()))
+}
+
+/// bitcoind credentials for `AsyncBitcoinClient`.
+pub fn rpc_auth() -> Result<Auth> {
+    Ok(Auth::UserPass(RPC_USER.into(), rpc_password()?.into()))
+}
+
+/// Writes `contents` to a file only our user can read. The daemons that read
+/// it run as our user or as root.
+pub fn write_private(path: &Path, contents: &str) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("writing {}", path.display()))?;
+    // `mode` only applies to new files
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    std::io::Write::write_all(&mut file, contents.as_bytes())?;
+    Ok(())
+}
+
+/// Writes the RPC credentials to a private config file. `prefix` is `bitcoin-`
+/// for lightningd.
+pub fn write_rpc_credentials(path: &Path, prefix: &str) -> Result<()> {
+    write_private(path, &format!("{0}rpcuser={1}\n{0}rpcpassword={2}\n", prefix, RPC_USER, rpc_password()?))
+}
+
+/// `peerswap.conf.template` with the current RPC password filled in.
+pub fn peerswap_conf() -> Result<String> {
+    let password = rpc_password()?;
+    Ok(std::fs::read_to_string(PEERSWAP_CONF_TEMPLATE)
+        .with_context(|| format!("reading {}", PEERSWAP_CONF_TEMPLATE))?
+        .lines()
+        .map(|l| match l.trim().starts_with("rpcpassword=") {
+            true => format!("rpcpassword=\"{}\"\n", password),
+            false => format!("{}\n", l),
+        })
+        .collect())
+}
+
+/// Writes the credential files a stack mounts into `dir`: `rpc.conf`,
+/// `lightningd.conf` and `peerswap.conf`.
+fn write_stack_config(dir: &Path) -> Result<()> {
+    write_rpc_credentials(&dir.join(RPC_CONF), "")?;
+    write_rpc_credentials(&dir.join(LIGHTNINGD_CONF), "bitcoin-")?;
+    write_private(&dir.join("peerswap.conf"), &peerswap_conf()?)
+}
+
+/// Writes the credential files of the long-lived stack into the working
+/// directory, keeping the password of an earlier `init-config` so a running
+/// stack keeps working.
+pub fn init_config() -> Result<()> {
+    if !Path::new(RPC_CONF).exists() {
+        new_rpc_password()?;
+    }
+    write_stack_config(Path::new("."))?;
+    println!("Wrote {}, {} and peerswap.conf", RPC_CONF, LIGHTNINGD_CONF);
+    Ok(())
+}
+
+/// `elements-cli` connected to the stack's elementsd. It reads the RPC
+/// password from the first line of stdin, so it never shows up in `ps`.
+pub fn elements_cli_command() -> Command {
+    if let Some(local) = process_env::active() {
+        return local.elements_cli();
+    }
+    let mut cmd = Command::new("docker");
+    cmd.args(["exec", "-i", &container("elementsd"), "elements-cli", "-chain=liquidregtest", &format!("-rpcuser={}", RPC_USER), "-stdinrpcpass", "-rpcport=7041"]);
+    cmd
+}
+
+/// `host:port` other nodes connect to `node` on.
+pub fn node_addr(node: &str) -> Result<String> {
+    match process_env::active() {
+        Some(local) => local.node_addr(node),
+        None => Ok(format!("{}:9735", node)),
+    }
+}
+
+/// A port nothing is listening on right now.
+pub fn free_port() -> Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+fn fresh_dir(path: &Path) -> Result<()> {
+    std::fs::create_dir_all(path).with_context(|| format!("creating {}", path.display()))?;
+    // bitcoind and elementsd run as our user and lightningd as root, so no one
+    // else needs in
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o700))?;
+    Ok(())
+}
+
+pub struct RegtestEnv {
+    project: String,
+    data_dir: PathBuf,
+    bitcoind_rpc_port: u16,
+    env: Vec<(String, String)>,
+}
+
+impl RegtestEnv {
+    /// Starts the stack with empty volumes under a new project name. Only one
+    /// stack can be active per process.
+    pub async fn up() -> Result<Self> {
+        let project = format!("peerswap-{}-{:08x}", std::process::id(), rand::random::<u32>());
+        let data_dir = std::env::temp_dir().join(&project);
+        let config_dir = data_dir.join("config");
+        fresh_dir(&data_dir)?;
+        for dir in [".bitcoin", ".elements"].into_iter().chain(NODES.map(|n| format!(".lightning-{}", n)).iter().map(String::as_str)) {
+            fresh_dir(&data_dir.join(dir))?;
+        }
+        fresh_dir(&config_dir)?;
+        std::fs::copy("policy.conf", config_dir.join("policy.conf")).context("copying policy.conf")?;
+        new_rpc_password()?;
+        write_stack_config(&config_dir)?;
+        let owner = std::fs::metadata(&data_dir)?;
+
+        let bitcoind_rpc_port = free_port()?;
+        let mut env = vec![
+            ("REGTEST_DATA_DIR".to_string(), data_dir.display().to_string()),
+            ("REGTEST_CONFIG_DIR".to_string(), config_dir.display().to_string()),
+            ("CONTAINER_PREFIX".to_string(), format!("{}-", project)),
+            ("BITCOIND_RPC_PORT".to_string(), bitcoind_rpc_port.to_string()),
+            ("ELEMENTSD_RPC_PORT".to_string(), free_port()?.to_string()),
+            // bitcoind and elementsd run as us, so they can read the private config files
+            ("REGTEST_UID".to_string(), owner.uid().to_string()),
+            ("REGTEST_GID".to_string(), owner.gid().to_string()),
+        ];
+        for node in NODES {
+            let node = node.to_uppercase();
+            env.push((format!("{}_PORT", node), free_port()?.to_string()));
+            env.push((format!("{}_GRPC_PORT", node), free_port()?.to_string()));
+        }
+
+        let stack = RegtestEnv { project, data_dir, bitcoind_rpc_port, env };
+        ACTIVE
+            .set(Active { container_prefix: format!("{}-", stack.project), config_dir })
+            .map_err(|_| anyhow::anyhow!("A regtest stack is already running"))?;
+        println!("Starting regtest stack {} in {}", stack.project, stack.data_dir.display());
+        if let Err(e) = stack.compose(&["up", "-d"]).await {
+            stack.down(true).await?;
+            return Err(e);
+        }
+        Ok(stack)
+    }
+
+    async fn compose(&self, args: &[&str]) -> Result<String> {
+        let output = Command::new("docker")
+            .args(["compose", "-p", &self.project, "-f", COMPOSE_FILE])
+            .args(args)
+            .envs(self.env.iter().map(|(k, v)| (k, v)))
+            .output().await?;
+        if !output.status.success() {
+            anyhow::bail!("docker compose {}: {}", args.join(" "), String::from_utf8_lossy(&output.stderr));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    pub fn bitcoind_url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.bitcoind_rpc_port)
+    }
+
+    pub async fn wait_healthy(&self, btc: &BitcoinClient) -> Result<()> {
+        wait_healthy(btc).await?;
+        println!("Regtest stack {} is healthy", self.project);
+        Ok(())
+    }
+
+    /// Removes the containers and volumes. With `keep_logs` the container logs
+    /// are written to the data directory, which is then left in place.
+    pub async fn down(self, keep_logs: bool) -> Result<()> {
+        if keep_logs {
+            let logs = self.compose(&["logs", "--no-color", "--timestamps"]).await?;
+            let path = self.data_dir.join("compose.log");
+            std::fs::write(&path, logs)?;
+            println!("Kept logs of {} in {}", self.project, path.display());
+        }
+        self.compose(&["down", "--volumes", "--remove-orphans"]).await?;
+        if !keep_logs && let Err(e) = std::fs::remove_dir_all(&self.data_dir) {
+            // Files the daemons wrote may belong to their container users
+            println!("Could not remove {}: {}", self.data_dir.display(), e);
+        }
+        Ok(())
+    }
+}
+
+/// Waits until bitcoind and elementsd answer RPCs and every node is up
+/// with the PeerSwap plugin loaded.
+pub async fn wait_healthy(btc: &BitcoinClient) -> Result<()> {
+    wait_for("bitcoind RPC", || async {
+        btc.get_block_count().await?;
+        Ok(())
+    })
+    .await?;
+    wait_for("elementsd RPC", || async {
+        elements_cli(&["getblockchaininfo"]).await?;
+        Ok(())
+    })
+    .await?;
+    for node in NODES {
+        wait_for(&format!("{} lightningd", node), || async {
+            cli(node, &["getinfo"]).await?;
+            Ok(())
+        })
+        .await?;
+        wait_for(&format!("{} peerswap plugin", node), || async {
+            let plugins = cli(node, &["plugin", "list"]).await?;
+            let loaded = plugins["plugins"]
+                .as_array()
+                .is_some_and(|ps| ps.iter().any(|p| p["name"].as_str().is_some_and(|n| n.ends_with("/peerswap")) && p["active"].as_bool() == Some(true)));
+            anyhow::ensure!(loaded, "peerswap not active yet");
+            // The plugin registers its RPCs once it has connected to its backends
+            cli(node, &["peerswap-listpeers"]).await?;
+            Ok(())
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+async fn wait_for<F, Fut>(what: &str, mut check: F) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<()>>,
+{
+    let mut last = None;
+    for _ in 0..120 {
+        match check().await {
+            Ok(()) => return Ok(()),
+            Err(e) => last = Some(e),
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    Err(last.unwrap_or_else(|| anyhow::anyhow!("no attempts"))).with_context(|| format!("Timeout waiting for {}", what))
+}
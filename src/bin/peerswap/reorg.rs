@@ -0,0 +1,183 @@
+//! Chain reorganisations on bitcoind, and scenarios that reorg out a channel
+//! funding transaction and a swap's opening transaction to see how the nodes
+//! recover.
+
+use anyhow::{Context, Result};
+use bitcoincore_rpc::bitcoin::Txid;
+use std::time::Duration;
+
+use super::bitcoin_rpc::AsyncBitcoinClient as BitcoinClient;
+use super::amount::Amount;
+use super::ids::Scid;
+use super::csv_claim::wait_for_opening_tx;
+use super::error::Error;
+use super::recorder;
+use super::setup::{close_channels, node_id};
+use super::{cli, get_channel_balance, SWAP_AMOUNT};
+
+// lightningd's default funding-confirms: a channel locks in at this depth
+const FUNDING_CONFIRMS: u64 = 3;
+
+/// What the blocks replacing the reorged ones contain.
+#[derive(Clone, Copy, Debug)]
+pub enum Replacement {
+    /// Whatever is in the mempool, so the orphaned transactions confirm again.
+    WithTransactions,
+    /// Nothing but the coinbase. The orphaned transactions stay in the mempool
+    /// until a later block picks them up.
+    Empty,
+}
+
+/// Replaces the last `depth` blocks with `depth + 1` new ones, so the new chain
+/// wins even once the old blocks are reconsidered. Returns the non-coinbase
+/// transactions of the orphaned blocks.
+pub async fn reorg(btc: &BitcoinClient, depth: u64, replacement: Replacement, mine_to: &bitcoincore_rpc::bitcoin::Address) -> Result<Vec<Txid>> {
+    anyhow::ensure!(depth > 0, "Reorg depth must be at least 1");
+    let tip = btc.get_block_count().await?;
+    // The genesis block cannot be invalidated
+    anyhow::ensure!(depth <= tip, "Cannot reorg {} blocks on a chain of height {}", depth, tip);
+    let mut orphaned = Vec::new();
+    let mut orphaned_txids = Vec::new();
+    for height in tip + 1 - depth..=tip {
+        let hash = btc.get_block_hash(height).await?;
+        let block = btc.get_block(&hash).await?;
+        orphaned_txids.extend(block.txdata.iter().skip(1).map(|tx| tx.compute_txid()));
+        orphaned.push(hash);
+    }
+
+    btc.invalidate_block(&orphaned[0]).await?;
+    match replacement {
+        Replacement::WithTransactions => {
+            btc.generate_to_address(depth + 1, mine_to).await?;
+        }
+        Replacement::Empty => {
+            for _ in 0..=depth {
+                btc.generate_block(mine_to, &[]).await?;
+            }
+        }
+    }
+    btc.reconsider_block(&orphaned[0]).await?;
+
+    let new_tip = btc.get_best_block_hash().await?;
+    anyhow::ensure!(!orphaned.contains(&new_tip), "Reorg did not stick: tip is still {}", new_tip);
+    println!(
+        "Reorged out {} block(s) with {} transaction(s), {:?}; new tip {}",
+        depth,
+        orphaned_txids.len(),
+        replacement,
+        new_tip
+    );
+    Ok(orphaned_txids)
+}
+
+async fn funded_channel(container: &str, funding_txid: &str) -> Result<Option<serde_json::Value>> {
+    Ok(cli(container, &["listfunds"]).await?["channels"]
+        .as_array()
+        .and_then(|chs| chs.iter().find(|c| c["funding_txid"].as_str() == Some(funding_txid)))
+        .cloned())
+}
+
+async fn wait_for_channel_state(container: &str, funding_txid: &str, state: &str) -> Result<serde_json::Value> {
+    for _ in 0..60 {
+        if let Some(ch) = funded_channel(container, funding_txid).await?
+            && ch["state"].as_str() == Some(state)
+        {
+            return Ok(ch);
+        }
+        recorder::sleep(Duration::from_secs(1)).await;
+    }
+    Err(Error::Timeout(format!("waiting for channel {} to reach {}", funding_txid, state)).into())
+}
+
+/// Opens a channel from `from` to `to`, reorgs out its funding transaction one
+/// block short of lock-in, and then reorgs the locked-in funding block again
+/// while keeping the transaction. Checks the channel's final scid points at the
+/// funding transaction on the new chain. `capacity` tags the channel: any
+/// channel of that size left between the two by an earlier run is closed
+/// first, and this run's channel is closed at the end.
+pub async fn run_funding_reorg(btc: &BitcoinClient, from: &str, to: &str, capacity: Amount, mine_to: &bitcoincore_rpc::bitcoin::Address) -> Result<()> {
+    close_channels(btc, from, to, capacity).await?;
+    let to_id = node_id(to).await?;
+    let funding_txid = cli(from, &["fundchannel", &to_id.to_string(), &capacity.to_sat()?.to_string()]).await?["txid"]
+        .as_str()
+        .context("No txid")?
+        .to_string();
+    btc.generate_to_address(FUNDING_CONFIRMS - 1, mine_to).await?;
+    recorder::sleep(Duration::from_secs(5)).await;
+
+    let reorged = reorg(btc, FUNDING_CONFIRMS - 1, Replacement::Empty, mine_to).await?;
+    anyhow::ensure!(
+        reorged.iter().any(|t| t.to_string() == funding_txid),
+        "Funding transaction {} was not in the reorged blocks", funding_txid
+    );
+    anyhow::ensure!(
+        btc.get_raw_mempool().await?.iter().any(|t| t.to_string() == funding_txid),
+        "Funding transaction {} did not return to the mempool", funding_txid
+    );
+    recorder::sleep(Duration::from_secs(5)).await;
+    let state = funded_channel(from, &funding_txid).await?.context("Channel vanished after the reorg")?["state"]
+        .as_str()
+        .unwrap_or("")
+        .to_string();
+    println!("Channel {} after reorging out its funding: {}", funding_txid, state);
+    anyhow::ensure!(state == "CHANNELD_AWAITING_LOCKIN", "Channel went to {} with an unconfirmed funding transaction", state);
+
+    btc.generate_to_address(6, mine_to).await?;
+    let ch = wait_for_channel_state(from, &funding_txid, "CHANNELD_NORMAL").await?;
+    let scid_before: Scid = ch["short_channel_id"].as_str().context("No scid")?.parse()?;
+
+    reorg(btc, 6, Replacement::WithTransactions, mine_to).await?;
+    btc.generate_to_address(1, mine_to).await?;
+    let ch = wait_for_channel_state(from, &funding_txid, "CHANNELD_NORMAL").await?;
+    let scid_after: Scid = ch["short_channel_id"].as_str().context("No scid")?.parse()?;
+    let block = btc.get_block(&btc.get_block_hash(scid_after.block.into()).await?).await?;
+    anyhow::ensure!(
+        block.txdata.get(scid_after.tx as usize).is_some_and(|tx| tx.compute_txid().to_string() == funding_txid),
+        "Scid {} does not point at funding transaction {} on the new chain", scid_after, funding_txid
+    );
+    println!("Channel {} survived a 6-block reorg: scid {} -> {}", funding_txid, scid_before, scid_after);
+    close_channels(btc, from, to, capacity).await?;
+    Ok(())
+}
+
+/// Runs a BTC swap-in from `initiator`, reorgs out its opening transaction
+/// after the first confirmation, and checks the swap still completes once the
+/// transaction confirms again.
+#[tracing::instrument(skip_all, fields(node = initiator, scid, asset = "btc", direction = "swap_in", swap_id = tracing::field::Empty))]
+pub async fn run_opening_reorg(btc: &BitcoinClient, initiator: &str, scid: Scid, mine_to: &bitcoincore_rpc::bitcoin::Address) -> Result<()> {
+    let channel_before = get_channel_balance(initiator, scid).await?;
+    let swap = cli(initiator, &["peerswap-swap-in", &scid.to_string(), &SWAP_AMOUNT.to_sat()?.to_string(), "btc", "10000"]).await?;
+    let swap_id = swap["id"].as_str().context("No swap id")?.to_string();
+    tracing::Span::current().record("swap_id", &swap_id);
+    let opening_tx_hex = wait_for_opening_tx(initiator, &swap_id).await?;
+    let tx: bitcoincore_rpc::bitcoin::Transaction = bitcoincore_rpc::bitcoin::consensus::deserialize(&hex::decode(&opening_tx_hex)?)?;
+    let opening_txid = tx.compute_txid();
+
+    btc.generate_to_address(1, mine_to).await?;
+    recorder::sleep(Duration::from_secs(2)).await;
+    let reorged = reorg(btc, 1, Replacement::Empty, mine_to).await?;
+    anyhow::ensure!(reorged.contains(&opening_txid), "Opening transaction {} was not in the reorged block", opening_txid);
+    anyhow::ensure!(btc.get_raw_mempool().await?.contains(&opening_txid), "Opening transaction {} did not return to the mempool", opening_txid);
+
+    let status = cli(initiator, &["peerswap-getswap", &swap_id]).await?;
+    let state = status["current"].as_str().unwrap_or("").to_string();
+    println!("Swap {} after reorging out its opening transaction: {}", swap_id, state);
+    anyhow::ensure!(state != "State_SwapCanceled", "Swap was canceled after the reorg");
+
+    for _ in 0..30 {
+        btc.generate_to_address(1, mine_to).await?;
+        recorder::sleep(Duration::from_secs(2)).await;
+        let status = cli(initiator, &["peerswap-getswap", &swap_id]).await?;
+        let state = status["current"].as_str().unwrap_or("");
+        tracing::debug!(state, "Polled swap");
+        anyhow::ensure!(state != "State_SwapCanceled", "Swap was canceled after the reorg");
+        if state == "State_ClaimedPreimage" || state == "State_ClaimedCoop" {
+            anyhow::ensure!(!btc.get_raw_mempool().await?.contains(&opening_txid), "Swap claimed with the opening transaction unconfirmed");
+            let channel_after = get_channel_balance(initiator, scid).await?;
+            println!("Swap {} recovered from the reorg: {} channel {} -> {}", swap_id, initiator, channel_before, channel_after);
+            anyhow::ensure!(channel_after > channel_before, "Swap-in did not move funds into the channel");
+            return Ok(());
+        }
+    }
+    Err(Error::Timeout(format!("waiting for swap {} to complete after the reorg", swap_id)).into())
+}
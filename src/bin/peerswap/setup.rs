@@ -0,0 +1,191 @@
+//! Scenario setup steps that look at the current state first, so reruns
+//! against a long-lived stack reuse nodes, channels and funds instead of
+//! piling up channels or failing on "already connected".
+
+use anyhow::{Context, Result};
+
+use super::bitcoin_rpc::AsyncBitcoinClient as BitcoinClient;
+use super::amount::Amount;
+use super::ids::{NodeId, PeerAddress, Scid};
+use super::premium::onchain_balance;
+use super::recorder;
+use super::regtest_env::node_addr;
+use super::setup_report::SetupReport;
+use super::{await_lockin, cli, elements_cli, get_channel_balance, invoice, liquid_fund_wallet, liquid_generate, liquid_send, neutral_address, newaddr, open_channel, peerswap_lbtc_addr};
+
+/// Mines 101 blocks to `node` unless it already has `min` confirmed BTC.
+pub async fn ensure_btc(btc: &BitcoinClient, report: &mut SetupReport, node: &str, min: Amount) -> Result<()> {
+    let balance = onchain_balance(node, "btc").await?;
+    if balance >= min {
+        report.reused(format!("{} BTC balance of {}", node, balance));
+        return Ok(());
+    }
+    btc.generate_to_address(101, &newaddr(node).await?).await?;
+    // Let lightningd pick up the new blocks
+    recorder::sleep(std::time::Duration::from_secs(5)).await;
+    report.created(format!("{} BTC funds (had {}, wanted {})", node, balance, min));
+    Ok(())
+}
+
+/// Creates and funds the elementsd `peerswap` wallet unless it is loaded and
+/// holds at least `min`.
+pub async fn ensure_liquid_wallet(report: &mut SetupReport, min: Amount) -> Result<()> {
+    let _ = elements_cli(&["createwallet", "peerswap"]).await; // ignore if exists
+    let _ = elements_cli(&["loadwallet", "peerswap"]).await; // ignore if loaded
+    let balance = match &elements_cli(&["-rpcwallet=peerswap", "getbalance"]).await?["bitcoin"] {
+        serde_json::Value::Null => Amount::ZERO,
+        btc => Amount::from_btc_str(&btc.to_string())?,
+    };
+    if balance >= min {
+        report.reused(format!("L-BTC wallet balance of {}", balance));
+        return Ok(());
+    }
+    liquid_fund_wallet().await?;
+    report.created(format!("L-BTC wallet funds (had {}, wanted {})", balance, min));
+    Ok(())
+}
+
+/// Sends `node` enough L-BTC from the elementsd wallet to hold at least `min`.
+pub async fn ensure_lbtc(report: &mut SetupReport, node: &str, min: Amount) -> Result<()> {
+    let balance = onchain_balance(node, "lbtc").await?;
+    if balance >= min {
+        report.reused(format!("{} L-BTC balance of {}", node, balance));
+        return Ok(());
+    }
+    let missing = min.checked_sub(balance)?;
+    liquid_send(&peerswap_lbtc_addr(node).await?, missing).await?;
+    liquid_generate(1).await?;
+    recorder::sleep(std::time::Duration::from_secs(5)).await;
+    report.created(format!("{} L-BTC top-up of {}", node, missing));
+    Ok(())
+}
+
+pub async fn node_id(node: &str) -> Result<NodeId> {
+    cli(node, &["getinfo"]).await?["id"].as_str().context("No id")?.parse()
+}
+
+/// Where other nodes reach `node`.
+pub async fn peer_address(node: &str) -> Result<PeerAddress> {
+    format!("{}@{}", node_id(node).await?, node_addr(node)?).parse()
+}
+
+/// Connects `from` to `to` unless they are connected already.
+pub async fn ensure_connected(report: &mut SetupReport, from: &str, to: &str) -> Result<()> {
+    let to_addr = peer_address(to).await?;
+    let peers = cli(from, &["listpeers", &to_addr.id.to_string()]).await?;
+    if peers["peers"].as_array().is_some_and(|ps| ps.iter().any(|p| p["connected"].as_bool() == Some(true))) {
+        report.reused(format!("connection {} -> {}", from, to));
+        return Ok(());
+    }
+    cli(from, &["connect", &to_addr.to_string()]).await?;
+    report.created(format!("connection {} -> {}", from, to));
+    Ok(())
+}
+
+/// Returns `count` active channels of `capacity` from `from` to `to`, reusing
+/// existing ones, waiting for those still awaiting lock-in, and opening the
+/// rest. Channels of other sizes belong to other scenarios and are left alone.
+pub async fn ensure_channels(
+    btc: &BitcoinClient,
+    report: &mut SetupReport,
+    from: &str,
+    to: &str,
+    count: usize,
+    capacity: Amount,
+    mine_to: &bitcoincore_rpc::bitcoin::Address,
+) -> Result<Vec<Scid>> {
+    ensure_connected(report, from, to).await?;
+    let to_id = node_id(to).await?;
+    let funds = cli(from, &["listfunds"]).await?;
+    let mut channels: Vec<_> = funds["channels"]
+        .as_array()
+        .context("No channels")?
+        .iter()
+        .filter(|c| c["peer_id"].as_str() == Some(&to_id.to_string()))
+        .filter(|c| c["amount_msat"].as_u64() == Some(capacity.to_msat().0))
+        .filter_map(|c| Some((c["state"].as_str()?, c["funding_txid"].as_str()?, c["short_channel_id"].as_str())))
+        .filter(|(state, _, _)| ["CHANNELD_NORMAL", "CHANNELD_AWAITING_LOCKIN"].contains(state))
+        .collect();
+    // Active channels first, so pending ones are only waited for when needed
+    channels.sort_by_key(|(state, _, _)| *state != "CHANNELD_NORMAL");
+
+    let mut scids = Vec::new();
+    for (state, funding_txid, scid) in channels.into_iter().take(count) {
+        let scid: Scid = match (state, scid) {
+            ("CHANNELD_NORMAL", Some(scid)) => scid.parse()?,
+            _ => await_lockin(btc, from, funding_txid, mine_to).await?,
+        };
+        report.reused(format!("channel {} -> {} {} ({})", from, to, scid, state));
+        scids.push(scid);
+    }
+    while scids.len() < count {
+        let scid = open_channel(btc, from, to_id, capacity, mine_to).await?;
+        report.created(format!("channel {} -> {} {} of {}", from, to, scid, capacity));
+        scids.push(scid);
+    }
+    Ok(scids)
+}
+
+/// Closes every open or opening channel of `capacity` from `from` to `to`, and
+/// mines the closing transactions. Scenarios that open a channel on every run
+/// give it a capacity of its own and clear it with this, so their channels do
+/// not pile up on a long-lived stack. Returns how many were closed.
+pub async fn close_channels(btc: &BitcoinClient, from: &str, to: &str, capacity: Amount) -> Result<usize> {
+    let to_id = node_id(to).await?.to_string();
+    let funds = cli(from, &["listfunds"]).await?;
+    let channel_ids: Vec<String> = funds["channels"]
+        .as_array()
+        .context("No channels")?
+        .iter()
+        .filter(|c| c["peer_id"].as_str() == Some(&to_id))
+        .filter(|c| c["amount_msat"].as_u64() == Some(capacity.to_msat().0))
+        .filter(|c| c["state"].as_str().is_some_and(|s| ["CHANNELD_NORMAL", "CHANNELD_AWAITING_LOCKIN"].contains(&s)))
+        .filter_map(|c| c["channel_id"].as_str().map(str::to_string))
+        .collect();
+    for channel_id in &channel_ids {
+        // Closes unilaterally if the peer has not agreed within a second
+        cli(from, &["close", channel_id, "1"]).await?;
+        println!("Closed channel {} {} -> {} of {}", channel_id, from, to, capacity);
+    }
+    if !channel_ids.is_empty() {
+        btc.generate_to_address(1, &neutral_address()).await?;
+    }
+    Ok(channel_ids.len())
+}
+
+/// Pays `to` over `scid` and nothing else, with a one-hop route.
+async fn pay_over(from: &str, to: &str, scid: Scid, amount: Amount) -> Result<()> {
+    let bolt11 = invoice(to, amount).await?;
+    let decoded = cli(from, &["decode", &bolt11]).await?;
+    let payment_hash = decoded["payment_hash"].as_str().context("No payment_hash")?;
+    let payment_secret = decoded["payment_secret"].as_str().context("No payment_secret")?;
+    let route = serde_json::json!([{
+        "id": node_id(to).await?.to_string(),
+        "channel": scid.to_string(),
+        "amount_msat": amount.to_msat().0,
+        "delay": decoded["min_final_cltv_expiry"].as_u64().context("No min_final_cltv_expiry")?,
+    }]);
+    let amount_msat = amount.to_msat().to_string();
+    cli(from, &["sendpay", &route.to_string(), payment_hash, "null", &amount_msat, &bolt11, payment_secret]).await?;
+    cli(from, &["waitsendpay", payment_hash]).await?;
+    Ok(())
+}
+
+/// Pays `to` over `scid` until it holds at least `min` on its side of it.
+pub async fn ensure_channel_balance(report: &mut SetupReport, from: &str, to: &str, scid: Scid, min: Amount) -> Result<()> {
+    let mut balance = get_channel_balance(to, scid).await?;
+    if balance >= min {
+        report.reused(format!("{} balance of {} on {}", to, balance, scid));
+        return Ok(());
+    }
+    for _ in 0..3 {
+        let missing = min.checked_sub(balance)?;
+        pay_over(from, to, scid, missing).await?;
+        report.created(format!("payment of {} to {} on {}", missing, to, scid));
+        balance = get_channel_balance(to, scid).await?;
+        if balance >= min {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("{} holds {} on {} after three payments, wanted {}", to, balance, scid, min)
+}
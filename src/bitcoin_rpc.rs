@@ -0,0 +1,158 @@
+//! Non-blocking wrapper around the blocking `bitcoincore_rpc` client, so
+//! bitcoind calls do not stall the tokio runtime. Every call goes through the
+//! RPC recorder.
+
+use bitcoincore_rpc::bitcoin::{Address, Block, BlockHash, Txid};
+use bitcoincore_rpc::json::{EstimateSmartFeeResult, GetMempoolEntryResult};
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use serde_json::{Value, json};
+use std::sync::Arc;
+
+use crate::error::{Error, Result};
+use crate::{recorder, telemetry};
+
+#[derive(Clone)]
+pub struct AsyncBitcoinClient {
+    inner: Arc<Client>,
+    url: String,
+    auth: Auth,
+    /// Name the calls are recorded under, `bitcoind` or `bitcoind:<wallet>`.
+    service: String,
+}
+
+impl AsyncBitcoinClient {
+    pub fn new(url: &str, auth: Auth) -> Result<Self> {
+        Ok(AsyncBitcoinClient {
+            inner: Arc::new(Client::new(url, auth.clone())?),
+            url: url.to_string(),
+            auth,
+            service: "bitcoind".into(),
+        })
+    }
+
+    /// A client for the bitcoind wallet `name`, which must be loaded.
+    pub fn wallet(&self, name: &str) -> Result<Self> {
+        let mut client = Self::new(&format!("{}/wallet/{}", self.url, name), self.auth.clone())?;
+        client.service = format!("bitcoind:{}", name);
+        Ok(client)
+    }
+
+    /// Runs `f` against the blocking client on tokio's blocking pool. `method`
+    /// and `params` describe the call for the recorder.
+    #[tracing::instrument(name = "rpc", skip_all, fields(node = %self.service, method))]
+    async fn call<T, F>(&self, method: &str, params: Value, f: F) -> Result<T>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned + Send + 'static,
+        F: FnOnce(&Client) -> bitcoincore_rpc::Result<T> + Send + 'static,
+    {
+        let client = self.inner.clone();
+        telemetry::rpc(recorder::call(&self.service, method, &params, async move {
+            Ok(tokio::task::spawn_blocking(move || f(&client))
+                .await?
+                .map_err(Error::from)?)
+        }))
+        .await
+        .map_err(Error::classify)
+    }
+
+    /// Calls an RPC the client has no typed method for.
+    pub async fn rpc<T>(&self, method: &'static str, args: Vec<Value>) -> Result<T>
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned + Send + 'static,
+    {
+        let params = Value::from(args.clone());
+        self.call(method, params, move |c| c.call(method, &args))
+            .await
+    }
+
+    pub async fn get_block_count(&self) -> Result<u64> {
+        self.call("getblockcount", json!([]), |c| c.get_block_count())
+            .await
+    }
+
+    pub async fn generate_to_address(
+        &self,
+        blocks: u64,
+        address: &Address,
+    ) -> Result<Vec<BlockHash>> {
+        let address = address.clone();
+        self.call(
+            "generatetoaddress",
+            json!([blocks, address.to_string()]),
+            move |c| c.generate_to_address(blocks, &address),
+        )
+        .await
+    }
+
+    pub async fn get_block_hash(&self, height: u64) -> Result<BlockHash> {
+        self.call("getblockhash", json!([height]), move |c| {
+            c.get_block_hash(height)
+        })
+        .await
+    }
+
+    pub async fn get_block(&self, hash: &BlockHash) -> Result<Block> {
+        let hash = *hash;
+        self.call("getblock", json!([hash.to_string()]), move |c| {
+            c.get_block(&hash)
+        })
+        .await
+    }
+
+    pub async fn get_best_block_hash(&self) -> Result<BlockHash> {
+        self.call("getbestblockhash", json!([]), |c| c.get_best_block_hash())
+            .await
+    }
+
+    pub async fn invalidate_block(&self, hash: &BlockHash) -> Result<()> {
+        let hash = *hash;
+        self.call("invalidateblock", json!([hash.to_string()]), move |c| {
+            c.invalidate_block(&hash)
+        })
+        .await
+    }
+
+    pub async fn reconsider_block(&self, hash: &BlockHash) -> Result<()> {
+        let hash = *hash;
+        self.call("reconsiderblock", json!([hash.to_string()]), move |c| {
+            c.reconsider_block(&hash)
+        })
+        .await
+    }
+
+    pub async fn get_raw_mempool(&self) -> Result<Vec<Txid>> {
+        self.call("getrawmempool", json!([]), |c| c.get_raw_mempool())
+            .await
+    }
+
+    /// Mines one block to `address` holding exactly `txids` from the mempool.
+    pub async fn generate_block(&self, address: &Address, txids: &[Txid]) -> Result<BlockHash> {
+        let txids: Vec<String> = txids.iter().map(|t| t.to_string()).collect();
+        let result: Value = self
+            .rpc(
+                "generateblock",
+                vec![address.to_string().into(), txids.into()],
+            )
+            .await?;
+        Ok(result["hash"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("No block hash"))?
+            .parse()
+            .map_err(anyhow::Error::from)?)
+    }
+
+    pub async fn get_mempool_entry(&self, txid: &Txid) -> Result<GetMempoolEntryResult> {
+        let txid = *txid;
+        self.call("getmempoolentry", json!([txid.to_string()]), move |c| {
+            c.get_mempool_entry(&txid)
+        })
+        .await
+    }
+
+    pub async fn estimate_smart_fee(&self, conf_target: u16) -> Result<EstimateSmartFeeResult> {
+        self.call("estimatesmartfee", json!([conf_target]), move |c| {
+            c.estimate_smart_fee(conf_target, None)
+        })
+        .await
+    }
+}
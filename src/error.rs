@@ -0,0 +1,302 @@
+//! Error kinds returned at the RPC boundary, so retry and scenario logic can
+//! branch on what went wrong instead of matching on message text.
+//!
+//! `lightning-cli`, `elements-cli`, bitcoind and Greenlight failures are parsed
+//! into a variant when the code or message says what happened, and fall back
+//! to `Rpc`, `Grpc` or `Command` with the raw code and message otherwise.
+//! Scenario code keeps using `anyhow` and gets the kind back with
+//! `e.downcast_ref::<Error>()`. The RPC recorder stores the kind itself, so a
+//! replayed error has the same variant as the recorded one.
+
+use serde::{Deserialize, Serialize};
+use std::process::Output;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+// CLN JSON-RPC error codes, from `common/jsonrpc_errors.h`
+const FUND_CANNOT_AFFORD: i64 = 300;
+const FUNDING_PEER_NOT_CONNECTED: i64 = 305;
+const FUND_CANNOT_AFFORD_WITH_EMERGENCY: i64 = 313;
+const CONNECT_DISCONNECTED_DURING: i64 = 402;
+// bitcoind and elementsd `RPC_WALLET_INSUFFICIENT_FUNDS`
+const RPC_WALLET_INSUFFICIENT_FUNDS: i64 = -6;
+
+#[derive(Debug, thiserror::Error, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "detail", rename_all = "snake_case")]
+pub enum Error {
+    #[error("Peer not connected: {0}")]
+    PeerNotConnected(String),
+    #[error("Insufficient funds: {0}")]
+    InsufficientFunds(String),
+    /// A call timed out while the node's signer was not running, so the node
+    /// could not sign what the call needed.
+    #[error("Signer offline: {0}")]
+    SignerOffline(String),
+    #[error("Timeout {0}")]
+    Timeout(String),
+    #[error("Swap refused ({reason:?}): {message}")]
+    SwapRefused {
+        reason: SwapRefusal,
+        message: String,
+    },
+    /// A JSON-RPC error from CLN, a plugin, bitcoind or elementsd.
+    #[error("RPC error {code}: {message}")]
+    Rpc { code: i64, message: String },
+    /// A Greenlight call that failed without a JSON-RPC error inside.
+    #[error("gRPC {code:?} ({}): {message}", *code as i32)]
+    Grpc {
+        #[serde(with = "grpc_code")]
+        code: tonic::Code,
+        message: String,
+    },
+    /// A CLI or docker command that failed without a JSON-RPC error.
+    #[error("{command} failed: {stderr}")]
+    Command { command: String, stderr: String },
+    /// Not recorded as a kind; replays as its message.
+    #[error(transparent)]
+    #[serde(skip)]
+    Other(#[from] anyhow::Error),
+}
+
+mod grpc_code {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(code: &tonic::Code, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_i32(*code as i32)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<tonic::Code, D::Error> {
+        i32::deserialize(d).map(tonic::Code::from_i32)
+    }
+}
+
+/// Why PeerSwap refused or canceled a swap, parsed from its error string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SwapRefusal {
+    /// The premium is above the initiator's `max_premium_ppm`.
+    PremiumTooHigh,
+    /// The peer is not on the allowlist or is marked suspicious.
+    PeerNotAllowed,
+    /// The amount is below the peer's `min_swap_amount_msat`.
+    BelowMinimum,
+    /// On-chain fees make the swap too expensive, or cannot be paid.
+    FeeTooHigh,
+}
+
+// The messages PeerSwap puts in a refused swap's error or `cancel_message`,
+// without the amounts it formats into some of them
+const REFUSALS: &[(&str, SwapRefusal)] = &[
+    (
+        "peer not allowed to request swaps",
+        SwapRefusal::PeerNotAllowed,
+    ),
+    ("exceeds the max premium", SwapRefusal::PremiumTooHigh),
+    (
+        "is less than the minimum swap amount",
+        SwapRefusal::BelowMinimum,
+    ),
+    ("fee too high", SwapRefusal::FeeTooHigh),
+];
+
+impl SwapRefusal {
+    /// The refusal whose PeerSwap message `message` contains, if any.
+    pub fn parse(message: &str) -> Option<Self> {
+        REFUSALS
+            .iter()
+            .find(|(pattern, _)| message.contains(pattern))
+            .map(|&(_, reason)| reason)
+    }
+}
+
+impl Error {
+    /// Maps a JSON-RPC error code and message to a kind. `method` tells
+    /// PeerSwap refusals apart from other errors that mention fees.
+    pub fn from_rpc(method: &str, code: i64, message: &str) -> Self {
+        let message = message.to_string();
+        let lower = message.to_lowercase();
+        match code {
+            FUND_CANNOT_AFFORD
+            | FUND_CANNOT_AFFORD_WITH_EMERGENCY
+            | RPC_WALLET_INSUFFICIENT_FUNDS => Error::InsufficientFunds(message),
+            FUNDING_PEER_NOT_CONNECTED | CONNECT_DISCONNECTED_DURING => {
+                Error::PeerNotConnected(message)
+            }
+            _ if method.starts_with("peerswap-swap")
+                && let Some(reason) = SwapRefusal::parse(&message) =>
+            {
+                Error::SwapRefused { reason, message }
+            }
+            _ if lower.contains("not connected") => Error::PeerNotConnected(message),
+            _ if lower.contains("insufficient funds") || lower.contains("cannot afford") => {
+                Error::InsufficientFunds(message)
+            }
+            _ => Error::Rpc { code, message },
+        }
+    }
+
+    /// Parses a failed `lightning-cli` or `elements-cli` run. `lightning-cli`
+    /// prints the JSON-RPC error on stdout, `elements-cli` as `error code: N`
+    /// on stderr.
+    pub fn from_cli_output(command: &str, method: &str, output: &Output) -> Self {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if let Ok(error) = serde_json::from_str::<serde_json::Value>(&stdout)
+            && let Some(code) = error["code"].as_i64()
+        {
+            return Error::from_rpc(method, code, error["message"].as_str().unwrap_or_default());
+        }
+        if let Some(rest) = stderr.trim().strip_prefix("error code: ")
+            && let Some((code, message)) = rest.split_once('\n')
+            && let Ok(code) = code.trim().parse()
+        {
+            let message = message.trim().trim_start_matches("error message:").trim();
+            return Error::from_rpc(method, code, message);
+        }
+        Error::Command {
+            command: command.to_string(),
+            stderr: stderr.trim().to_string(),
+        }
+    }
+
+    /// Gets the kind back from an error that went through the RPC recorder.
+    pub fn classify(error: anyhow::Error) -> Self {
+        match error.downcast::<Error>() {
+            Ok(error) => error,
+            Err(error) => Error::Other(error),
+        }
+    }
+}
+
+impl From<tonic::Status> for Error {
+    /// Greenlight forwards CLN errors as `RpcError { code: Some(N), message:
+    /// "..." }` inside the status message.
+    fn from(status: tonic::Status) -> Self {
+        let message = status.message();
+        if let Some((_, rest)) = message.split_once("code: Some(")
+            && let Some((code, rest)) = rest.split_once(')')
+            && let Ok(code) = code.parse()
+        {
+            let cln_message = rest
+                .split_once("message: \"")
+                .and_then(|(_, m)| m.split_once('"'))
+                .map(|(m, _)| m)
+                .unwrap_or(message);
+            let method = message
+                .strip_prefix("Error calling method ")
+                .and_then(|m| m.split(':').next())
+                .unwrap_or_default()
+                .to_lowercase();
+            return Error::from_rpc(&method, code, cln_message);
+        }
+        match status.code() {
+            tonic::Code::DeadlineExceeded => {
+                Error::Timeout(format!("calling the node: {}", message))
+            }
+            code => Error::Grpc {
+                code,
+                message: message.to_string(),
+            },
+        }
+    }
+}
+
+impl From<bitcoincore_rpc::Error> for Error {
+    fn from(error: bitcoincore_rpc::Error) -> Self {
+        match error {
+            bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Rpc(e)) => {
+                Error::from_rpc("", e.code as i64, &e.message)
+            }
+            e => Error::Other(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_cln_codes() {
+        assert!(matches!(
+            Error::from_rpc("fundchannel", FUND_CANNOT_AFFORD, "Could not afford"),
+            Error::InsufficientFunds(_)
+        ));
+        assert!(matches!(
+            Error::from_rpc("connect", CONNECT_DISCONNECTED_DURING, "peer went away"),
+            Error::PeerNotConnected(_)
+        ));
+        assert!(matches!(
+            Error::from_rpc("getinfo", -32601, "Unknown command"),
+            Error::Rpc { code: -32601, .. }
+        ));
+    }
+
+    #[test]
+    fn maps_greenlight_statuses() {
+        let status = tonic::Status::unknown(
+            "Error calling method FundChannel: RpcError { code: Some(300), message: \"Could not afford 100000sat\", data: None }",
+        );
+        match Error::from(status) {
+            Error::InsufficientFunds(message) => assert_eq!(message, "Could not afford 100000sat"),
+            e => panic!("unexpected {:?}", e),
+        }
+        // The message alone does not make it a signer problem
+        assert!(matches!(
+            Error::from(tonic::Status::unavailable("hsmd is restarting")),
+            Error::Grpc {
+                code: tonic::Code::Unavailable,
+                ..
+            }
+        ));
+        assert!(matches!(
+            Error::from(tonic::Status::deadline_exceeded("slow")),
+            Error::Timeout(_)
+        ));
+    }
+
+    #[test]
+    fn refusals_only_match_peerswap_messages() {
+        assert_eq!(
+            SwapRefusal::parse("peer not allowed to request swaps"),
+            Some(SwapRefusal::PeerNotAllowed)
+        );
+        assert_eq!(
+            SwapRefusal::parse("insufficient fee budget for premium"),
+            None
+        );
+        assert!(matches!(
+            Error::from_rpc("peerswap-swap-out", -1, "peer not allowed to request swaps"),
+            Error::SwapRefused {
+                reason: SwapRefusal::PeerNotAllowed,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn kinds_round_trip_through_json() {
+        for error in [
+            Error::SignerOffline("after 30s".into()),
+            Error::SwapRefused {
+                reason: SwapRefusal::BelowMinimum,
+                message: "m".into(),
+            },
+            Error::Grpc {
+                code: tonic::Code::Unavailable,
+                message: "m".into(),
+            },
+            Error::Command {
+                command: "docker stop".into(),
+                stderr: "no such container".into(),
+            },
+        ] {
+            let json = serde_json::to_value(&error).unwrap();
+            let back: Error = serde_json::from_value(json).unwrap();
+            assert_eq!(back.to_string(), error.to_string());
+            assert_eq!(
+                std::mem::discriminant(&back),
+                std::mem::discriminant(&error)
+            );
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Node ids, short channel ids and peer addresses, parsed once at the RPC
+//! boundary instead of being passed around as strings and byte slices.
+
+use anyhow::{Context, Result};
+use bitcoincore_rpc::bitcoin::secp256k1::PublicKey;
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+
+// The port lightningd listens on when an address does not name one
+const DEFAULT_PORT: u16 = 9735;
+
+/// A Lightning node id: a compressed secp256k1 public key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(PublicKey);
+
+impl NodeId {
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        PublicKey::from_slice(bytes)
+            .map(NodeId)
+            .with_context(|| format!("invalid node id {}", hex::encode(bytes)))
+    }
+
+    /// The 33-byte compressed key, as Greenlight RPCs take it.
+    pub fn to_vec(self) -> Vec<u8> {
+        self.0.serialize().to_vec()
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        PublicKey::from_str(s)
+            .map(NodeId)
+            .with_context(|| format!("invalid node id {}", s))
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A short channel id in CLN's `<block>x<tx>x<output>` form.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Scid {
+    pub block: u32,
+    pub tx: u32,
+    pub output: u16,
+}
+
+impl FromStr for Scid {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse = || -> Option<Scid> {
+            let mut parts = s.split('x');
+            let scid = Scid {
+                block: parts.next()?.parse().ok()?,
+                tx: parts.next()?.parse().ok()?,
+                output: parts.next()?.parse().ok()?,
+            };
+            // Block heights and tx indices are 24 bits in the encoded form
+            (parts.next().is_none() && scid.block < 1 << 24 && scid.tx < 1 << 24).then_some(scid)
+        };
+        parse().with_context(|| format!("invalid short channel id {}", s))
+    }
+}
+
+impl fmt::Display for Scid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}x{}", self.block, self.tx, self.output)
+    }
+}
+
+/// Where to reach a peer, as `connect` takes it: `<id>@<host>:<port>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerAddress {
+    pub id: NodeId,
+    pub host: String,
+    pub port: u16,
+}
+
+impl PeerAddress {
+    /// Builds an address from a bound host and port, bracketing a bare IPv6
+    /// host so the port stays unambiguous.
+    pub fn new(id: NodeId, host: &str, port: u16) -> Self {
+        let host = match host.parse::<Ipv6Addr>() {
+            Ok(ip) => format!("[{}]", ip),
+            Err(_) => host.to_string(),
+        };
+        PeerAddress { id, host, port }
+    }
+}
+
+impl FromStr for PeerAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (id, addr) = s
+            .split_once('@')
+            .with_context(|| format!("peer address {} has no @", s))?;
+        // An IPv6 host is in brackets, so its colons are not taken for a port
+        let (host, port) = match addr.strip_prefix('[') {
+            Some(rest) => {
+                let (ip, rest) = rest
+                    .split_once(']')
+                    .with_context(|| format!("unclosed [ in peer address {}", s))?;
+                let port = match rest {
+                    "" => None,
+                    rest => Some(
+                        rest.strip_prefix(':')
+                            .with_context(|| format!("junk after ] in peer address {}", s))?,
+                    ),
+                };
+                (&addr[..ip.len() + 2], port)
+            }
+            None => {
+                let (host, port) = match addr.split_once(':') {
+                    Some((host, port)) => (host, Some(port)),
+                    None => (addr, None),
+                };
+                anyhow::ensure!(
+                    !port.is_some_and(|p| p.contains(':')),
+                    "IPv6 host in peer address {} needs brackets",
+                    s
+                );
+                (host, port)
+            }
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .with_context(|| format!("invalid port in peer address {}", s))?,
+            None => DEFAULT_PORT,
+        };
+        anyhow::ensure!(host != "[]", "peer address {} has no host", s);
+        anyhow::ensure!(!host.is_empty(), "peer address {} has no host", s);
+        Ok(PeerAddress {
+            id: id.parse()?,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}:{}", self.id, self.host, self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The secp256k1 generator
+    const ID: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    #[test]
+    fn node_id_round_trips() {
+        let id: NodeId = ID.parse().unwrap();
+        assert_eq!(id.to_string(), ID);
+        assert_eq!(NodeId::from_slice(&id.to_vec()).unwrap(), id);
+        assert!("02abcd".parse::<NodeId>().is_err());
+    }
+
+    #[test]
+    fn parses_scids() {
+        let scid: Scid = "103x1x0".parse().unwrap();
+        assert_eq!(
+            scid,
+            Scid {
+                block: 103,
+                tx: 1,
+                output: 0
+            }
+        );
+        assert_eq!(scid.to_string(), "103x1x0");
+        for bad in [
+            "103x1",
+            "103x1x0x0",
+            "103:1:0",
+            "16777216x0x0",
+            "1x16777216x0",
+            "1x1x65536",
+            "",
+        ] {
+            assert!(bad.parse::<Scid>().is_err(), "{} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn parses_peer_addresses() {
+        let addr: PeerAddress = format!("{}@bob:19846", ID).parse().unwrap();
+        assert_eq!((addr.host.as_str(), addr.port), ("bob", 19846));
+        assert_eq!(addr.to_string(), format!("{}@bob:19846", ID));
+
+        let addr: PeerAddress = format!("{}@bob", ID).parse().unwrap();
+        assert_eq!((addr.host.as_str(), addr.port), ("bob", DEFAULT_PORT));
+    }
+
+    #[test]
+    fn parses_ipv6_peer_addresses() {
+        let addr: PeerAddress = format!("{}@[::1]:19846", ID).parse().unwrap();
+        assert_eq!((addr.host.as_str(), addr.port), ("[::1]", 19846));
+        assert_eq!(addr.to_string(), format!("{}@[::1]:19846", ID));
+
+        let addr: PeerAddress = format!("{}@[::1]", ID).parse().unwrap();
+        assert_eq!((addr.host.as_str(), addr.port), ("[::1]", DEFAULT_PORT));
+    }
+
+    #[test]
+    fn brackets_bound_ipv6_hosts() {
+        let id: NodeId = ID.parse().unwrap();
+        let addr = PeerAddress::new(id, "::1", 19846);
+        assert_eq!(addr.to_string(), format!("{}@[::1]:19846", ID));
+        assert_eq!(addr.to_string().parse::<PeerAddress>().unwrap(), addr);
+
+        let addr = PeerAddress::new(id, "127.0.0.1", 19846);
+        assert_eq!(addr.to_string(), format!("{}@127.0.0.1:19846", ID));
+        assert_eq!(PeerAddress::new(id, "[::1]", 19846).host, "[::1]");
+    }
+
+    #[test]
+    fn rejects_bad_peer_addresses() {
+        for bad in [
+            "bob:9735".to_string(),
+            format!("{}@", ID),
+            format!("{}@[]:9735", ID),
+            format!("{}@[::1", ID),
+            format!("{}@[::1]9735", ID),
+            format!("{}@::1", ID),
+            format!("{}@bob:port", ID),
+            "02abcd@bob:9735".to_string(),
+        ] {
+            assert!(bad.parse::<PeerAddress>().is_err(), "{} parsed", bad);
+        }
+    }
+}
@@ -0,0 +1,11 @@
+//! Modules both binaries use: amounts and ids, error kinds, the bitcoind
+//! client, RPC recording, telemetry, metrics and the setup report.
+
+pub mod amount;
+pub mod bitcoin_rpc;
+pub mod error;
+pub mod ids;
+pub mod metrics;
+pub mod recorder;
+pub mod setup_report;
+pub mod telemetry;
@@ -1,35 +1,64 @@
 use anyhow::{Context, Result};
 use bip39::{Language, Mnemonic};
-use bitcoincore_rpc::{Auth, Client as BitcoinClient, RpcApi};
+use bitcoin_rpc::AsyncBitcoinClient as BitcoinClient;
+use bitcoincore_rpc::Auth;
+use error::Error;
 use gl_client::bitcoin::Network;
 use gl_client::credentials::{Device, Nobody};
 use gl_client::node::ClnClient;
+use gl_client::pb::cln::pay_response::PayStatus;
 use gl_client::pb::cln::{
-    AmountOrAll, ConnectRequest, FundchannelRequest, GetinfoRequest, ListfundsRequest,
-    ListpeersRequest, NewaddrRequest,
+    AmountOrAll, AmountOrAny, ChannelState, ConnectRequest, FundchannelRequest, GetinfoRequest,
+    InvoiceRequest, ListfundsRequest, ListinvoicesRequest, ListpeersRequest, NewaddrRequest,
+    PayRequest, StopRequest,
 };
 use gl_client::scheduler::Scheduler;
 use gl_client::signer::Signer;
+use gl_client_tryout::amount::Amount;
+use gl_client_tryout::{bitcoin_rpc, error, ids, metrics, recorder, setup_report, telemetry};
+use ids::{NodeId, PeerAddress};
+use retry::{CallStats, Idempotency, RetryPolicy};
+use secret::{Secret, SecretUrl};
+use setup_report::SetupReport;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::fs::File;
+use std::future::Future;
 use std::io::{Read, Write};
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::mpsc;
+use tracing::Instrument;
+
+#[cfg(test)]
+mod mock_greenlight;
+mod retry;
+mod secret;
 
 const NETWORK: Network = Network::Regtest;
 
 #[derive(Debug, serde::Deserialize)]
 struct TestServerMetadata {
     scheduler_grpc_uri: String,
-    bitcoind_rpc_uri: String,
+    bitcoind_rpc_uri: SecretUrl,
     ca_crt_path: String,
     nobody_crt_path: String,
-    nobody_key_path: String,
+    nobody_key_path: Secret<String>,
 }
 
+const CHANNEL_AMOUNT: Amount = Amount::from_sat(100_000);
+// Room for the funding transaction fee on top of the channel amount
+const FUNDING_FEE_RESERVE: Amount = Amount::from_sat(10_000);
+// Paid each way over the channel before and after restarting the nodes
+const PAYMENT_AMOUNT: Amount = Amount::from_sat(5_000);
+const METRICS_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+
 const GL_TESTSERVER_METADATA_PATH: &str = "/repo/.gltestserver/metadata.json";
 const CREDS_FILE_NAME: &str = "creds";
+// CLN's `INVOICE_LABEL_ALREADY_EXISTS`
+const INVOICE_LABEL_ALREADY_EXISTS: i64 = 900;
 const SEED_FILE_NAME: &str = "seed";
 
 fn load_testserver_config() -> Result<TestServerMetadata> {
@@ -37,12 +66,15 @@ fn load_testserver_config() -> Result<TestServerMetadata> {
     let mut config: TestServerMetadata = serde_json::from_str(&content)?;
     // Replace localhost with 127.0.0.1 in bitcoind URI to avoid IPv6 resolution issues in Docker
     // But keep localhost for scheduler/grpc URIs because the TLS certificate is valid for localhost
-    config.bitcoind_rpc_uri = config.bitcoind_rpc_uri.replace("localhost", "127.0.0.1");
+    let url = config.bitcoind_rpc_uri.expose_mut();
+    if url.host_str() == Some("localhost") {
+        url.set_host(Some("127.0.0.1"))?;
+    }
     Ok(config)
 }
 
-fn create_bitcoin_client(rpc_uri: &str) -> Result<BitcoinClient> {
-    let url = url::Url::parse(rpc_uri)?;
+fn create_bitcoin_client(rpc_uri: &SecretUrl) -> Result<BitcoinClient> {
+    let url = rpc_uri.expose();
     let host = format!(
         "{}://{}:{}",
         url.scheme(),
@@ -54,19 +86,32 @@ fn create_bitcoin_client(rpc_uri: &str) -> Result<BitcoinClient> {
         url.username().to_string(),
         url.password().unwrap_or("").to_string(),
     );
-    BitcoinClient::new(&host, auth).map_err(Into::into)
+    Ok(BitcoinClient::new(&host, auth)?)
 }
 
 #[allow(dead_code)]
 struct GlNode {
-    node: ClnClient,
+    /// Name the node's calls are recorded under.
+    name: &'static str,
+    /// `None` while replaying, when no node is scheduled.
+    node: Option<ClnClient>,
+    /// Finds the node again after it was rescheduled; `None` while replaying.
+    scheduler: Option<Scheduler<Device>>,
+    /// Per-method overrides of the default retry policies.
+    policies: HashMap<&'static str, RetryPolicy>,
+    stats: BTreeMap<&'static str, CallStats>,
+    /// Whether the signer task is running; a node without its signer cannot
+    /// answer calls that need a signature and leaves them to time out.
+    signer_up: Arc<AtomicBool>,
     _shutdown_tx: mpsc::Sender<()>,
 }
 
 impl GlNode {
+    #[tracing::instrument(skip_all, fields(node = name))]
     async fn new(
+        name: &'static str,
         creds_dir: &str,
-        config: &TestServerMetadata,
+        ca: &[u8],
         nobody_creds: &Nobody,
         scheduler_uri: String,
     ) -> Result<Self> {
@@ -76,50 +121,86 @@ impl GlNode {
         fs::create_dir_all(creds_dir)?;
         let seed = Self::load_or_create_seed(&seed_path)?;
 
-        let ca = fs::read(&config.ca_crt_path)?;
-
         let device = if Path::new(&creds_path).exists() {
-            Device::from_path(&creds_path).with_ca(ca)
+            tracing::info!(creds = %creds_path, "Reusing persisted credentials");
+            Device::from_path(&creds_path).with_ca(ca.to_vec())
         } else {
-            let signer = Signer::new(seed.to_vec(), NETWORK, nobody_creds.clone())?;
-            let scheduler = Scheduler::with(
-                NETWORK,
-                nobody_creds.clone(),
-                config.scheduler_grpc_uri.clone(),
-            )
-            .await?;
+            let signer = Signer::new(seed.expose().to_vec(), NETWORK, nobody_creds.clone())?;
+            let scheduler =
+                Scheduler::with(NETWORK, nobody_creds.clone(), scheduler_uri.clone()).await?;
             let reg = scheduler.register(&signer, None).await?;
-            let device = Device::from_bytes(reg.creds).with_ca(ca.clone());
+            let device = Device::from_bytes(reg.creds).with_ca(ca.to_vec());
             File::create(&creds_path)?.write_all(&device.to_bytes())?;
+            tracing::info!(creds = %creds_path, "Registered a new node");
             device
         };
 
-        let signer = Signer::new(seed.to_vec(), NETWORK, device.clone())?;
+        let signer = Signer::new(seed.expose().to_vec(), NETWORK, device.clone())?;
         let (_shutdown_tx, shutdown_rx) = mpsc::channel::<()>(1);
 
         let signer_scheduler_uri = scheduler_uri.clone();
-        tokio::spawn(async move {
-            if let Err(e) = signer
-                .run_forever_with_uri(shutdown_rx, signer_scheduler_uri)
-                .await
-            {
-                eprintln!("Signer error: {:?}", e);
+        let signer_up = Arc::new(AtomicBool::new(true));
+        let task_signer_up = signer_up.clone();
+        tokio::spawn(
+            async move {
+                tracing::info!("Signer started");
+                metrics::set_signer_up(name, true);
+                match signer
+                    .run_forever_with_uri(shutdown_rx, signer_scheduler_uri)
+                    .await
+                {
+                    Ok(()) => tracing::info!("Signer stopped"),
+                    Err(e) => tracing::error!(error = ?e, "Signer stopped"),
+                }
+                task_signer_up.store(false, Ordering::Relaxed);
+                metrics::set_signer_up(name, false);
             }
-        });
+            .instrument(tracing::info_span!("signer", node = name)),
+        );
 
-        let scheduler =
-            Scheduler::with(NETWORK, device.clone(), config.scheduler_grpc_uri.clone()).await?;
+        let scheduler = Scheduler::with(NETWORK, device.clone(), scheduler_uri).await?;
         let node: ClnClient = scheduler.node().await?;
 
-        Ok(Self { node, _shutdown_tx })
+        Ok(Self {
+            name,
+            node: Some(node),
+            scheduler: Some(scheduler),
+            policies: HashMap::new(),
+            stats: BTreeMap::new(),
+            signer_up,
+            _shutdown_tx,
+        })
+    }
+
+    /// A node that answers from the RPC recording only.
+    fn replayed(name: &'static str) -> Self {
+        Self {
+            name,
+            node: None,
+            scheduler: None,
+            policies: HashMap::new(),
+            stats: BTreeMap::new(),
+            signer_up: Arc::new(AtomicBool::new(false)),
+            _shutdown_tx: mpsc::channel(1).0,
+        }
     }
 
-    fn load_or_create_seed(path: &str) -> Result<[u8; 32]> {
+    fn service(&self) -> String {
+        format!("cln:{}", self.name)
+    }
+
+    fn client(&mut self) -> Result<&mut ClnClient> {
+        self.node
+            .as_mut()
+            .context("No Greenlight node to call while replaying")
+    }
+
+    fn load_or_create_seed(path: &str) -> Result<Secret<[u8; 32]>> {
         if Path::new(path).exists() {
             let mut file = File::open(path)?;
             let mut seed = [0u8; 32];
             file.read_exact(&mut seed)?;
-            return Ok(seed);
+            return Ok(Secret::new(seed));
         }
 
         let mut rng = rand::thread_rng();
@@ -128,150 +209,656 @@ impl GlNode {
 
         File::create(path)?.write_all(&seed)?;
 
-        Ok(seed)
+        Ok(Secret::new(seed))
     }
 
-    async fn get_info(&mut self) -> Result<gl_client::pb::cln::GetinfoResponse> {
-        Ok(self
-            .node
-            .getinfo(GetinfoRequest::default())
-            .await?
-            .into_inner())
+    /// Overrides the deadline and retries of `method`, e.g. `getinfo`.
+    // Only the tests tune policies and read the stats back
+    #[allow(dead_code)]
+    fn set_policy(&mut self, method: &'static str, policy: RetryPolicy) {
+        self.policies.insert(method, policy);
+    }
+
+    /// Calls, retries and latency per method since the node was started.
+    // Only the tests tune policies and read the stats back
+    #[allow(dead_code)]
+    fn call_stats(&self) -> &BTreeMap<&'static str, CallStats> {
+        &self.stats
+    }
+
+    fn print_call_stats(&self) {
+        println!("{} calls:", self.name);
+        for (method, stats) in &self.stats {
+            println!(
+                "  {}: {} calls, {} retries, {} reconnects, {} failed, mean {:?}, max {:?}",
+                method,
+                stats.calls,
+                stats.retries,
+                stats.reconnects,
+                stats.failures,
+                stats.mean_latency(),
+                stats.max_latency
+            );
+        }
+    }
+
+    /// Asks the scheduler where the node runs now, starting it if needed, and
+    /// connects to it with the device's TLS identity.
+    #[tracing::instrument(skip_all, fields(node = self.name))]
+    async fn reconnect(&mut self) -> Result<()> {
+        let scheduler = self
+            .scheduler
+            .as_ref()
+            .context("No scheduler to ask while replaying")?;
+        self.node = Some(scheduler.node().await?);
+        tracing::info!("Reconnected to the node");
+        Ok(())
+    }
+
+    /// Calls the node under the method's retry policy, recording the exchange
+    /// and its stats. If the connection was lost, reconnects once and sends
+    /// the request again if it is safe to repeat.
+    async fn call<Req, Resp, F, Fut>(
+        &mut self,
+        method: &'static str,
+        idempotency: Idempotency,
+        request: Req,
+        rpc: F,
+    ) -> error::Result<Resp>
+    where
+        Req: Clone + serde::Serialize,
+        Resp: serde::Serialize + serde::de::DeserializeOwned,
+        F: Fn(ClnClient, Req) -> Fut,
+        Fut: Future<Output = Result<tonic::Response<Resp>, tonic::Status>>,
+    {
+        let policy = self
+            .policies
+            .get(method)
+            .copied()
+            .unwrap_or_else(|| RetryPolicy::default_for(idempotency));
+        let service = self.service();
+        let started = std::time::Instant::now();
+        let mut retries = 0;
+        let mut reconnected = false;
+        let result = telemetry::rpc(recorder::call(&service, method, &request, async {
+            loop {
+                let client = self.client()?.clone();
+                let (result, n) = retry::run(policy, idempotency, || {
+                    let rpc = rpc(client.clone(), request.clone());
+                    async move {
+                        rpc.await
+                            .map(tonic::Response::into_inner)
+                            .map_err(Error::from)
+                    }
+                })
+                .await;
+                retries += n;
+                let signer_up = self.signer_up.load(Ordering::Relaxed);
+                let result = result.map_err(|e| match e {
+                    Error::Timeout(what) if !signer_up => Error::SignerOffline(what),
+                    e => e,
+                });
+                match result {
+                    Err(e) if !reconnected && retry::is_connection_lost(&e) => {
+                        tracing::warn!(error = %e, "Lost the connection to the node");
+                        self.reconnect().await?;
+                        reconnected = true;
+                        // The node may have run a request that moves funds before it went away
+                        if idempotency == Idempotency::Unsafe {
+                            return Err(e.into());
+                        }
+                    }
+                    result => return Ok(result?),
+                }
+            }
+        }))
+        .await
+        .map_err(Error::classify);
+        self.stats.entry(method).or_default().record(
+            retries,
+            reconnected,
+            started.elapsed(),
+            result.is_ok(),
+        );
+        result
     }
 
-    async fn new_address(&mut self) -> Result<String> {
+    #[tracing::instrument(name = "rpc", skip_all, fields(node = self.name, method = "getinfo"))]
+    async fn get_info(&mut self) -> error::Result<gl_client::pb::cln::GetinfoResponse> {
+        self.call(
+            "getinfo",
+            Idempotency::Safe,
+            GetinfoRequest::default(),
+            |mut node, req| async move { node.getinfo(req).await },
+        )
+        .await
+    }
+
+    #[tracing::instrument(name = "rpc", skip_all, fields(node = self.name, method = "newaddr"))]
+    async fn new_address(&mut self) -> error::Result<String> {
+        // A repeat hands out another address, which is harmless
         let resp = self
-            .node
-            .new_addr(NewaddrRequest::default())
+            .call(
+                "newaddr",
+                Idempotency::Safe,
+                NewaddrRequest::default(),
+                |mut node, req| async move { node.new_addr(req).await },
+            )
+            .await?;
+        Ok(resp.bech32.context("no bech32 address returned")?)
+    }
+
+    #[tracing::instrument(name = "rpc", skip_all, fields(node = self.name, method = "listfunds"))]
+    async fn list_funds(&mut self) -> error::Result<gl_client::pb::cln::ListfundsResponse> {
+        self.call(
+            "listfunds",
+            Idempotency::Safe,
+            ListfundsRequest::default(),
+            |mut node, req| async move { node.list_funds(req).await },
+        )
+        .await
+    }
+
+    #[tracing::instrument(name = "rpc", skip_all, fields(node = self.name, method = "connect"))]
+    async fn connect_peer(&mut self, peer: &PeerAddress) -> error::Result<()> {
+        let request = ConnectRequest {
+            id: peer.to_string(),
+            host: None,
+            port: None,
+        };
+        self.call(
+            "connect",
+            Idempotency::Safe,
+            request,
+            |mut node, req| async move { node.connect_peer(req).await },
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "rpc", skip_all, fields(node = self.name, method = "fundchannel"))]
+    async fn fund_channel(&mut self, node_id: NodeId, amount: Amount) -> error::Result<()> {
+        let request = FundchannelRequest {
+            id: node_id.to_vec(),
+            amount: Some(AmountOrAll {
+                value: Some(gl_client::pb::cln::amount_or_all::Value::Amount(
+                    gl_client::pb::cln::Amount {
+                        msat: amount.to_msat().0,
+                    },
+                )),
+            }),
+            ..Default::default()
+        };
+        self.call(
+            "fundchannel",
+            Idempotency::Unsafe,
+            request,
+            |mut node, req| async move { node.fund_channel(req).await },
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "rpc", skip_all, fields(node = self.name, method = "listpeers"))]
+    async fn list_peers(&mut self) -> error::Result<gl_client::pb::cln::ListpeersResponse> {
+        self.call(
+            "listpeers",
+            Idempotency::Safe,
+            ListpeersRequest::default(),
+            |mut node, req| async move { node.list_peers(req).await },
+        )
+        .await
+    }
+
+    async fn confirmed_balance(&mut self) -> Result<Amount> {
+        Ok(self
+            .list_funds()
             .await?
-            .into_inner();
-        resp.bech32.context("no bech32 address returned")
+            .outputs
+            .iter()
+            .filter(|o| {
+                o.status()
+                    == gl_client::pb::cln::listfunds_outputs::ListfundsOutputsStatus::Confirmed
+            })
+            .map(|o| Amount::from_msat(o.amount_msat.as_ref().map(|a| a.msat).unwrap_or(0)))
+            .sum::<Result<Amount, _>>()?)
     }
 
-    async fn list_funds(&mut self) -> Result<gl_client::pb::cln::ListfundsResponse> {
+    async fn is_connected(&mut self, peer_id: NodeId) -> Result<bool> {
         Ok(self
-            .node
-            .list_funds(ListfundsRequest::default())
+            .list_peers()
             .await?
-            .into_inner())
+            .peers
+            .iter()
+            .any(|p| p.id == peer_id.to_vec() && p.connected))
     }
 
-    async fn connect_peer(&mut self, node_id: &str, host: &str, port: u32) -> Result<()> {
-        self.node
-            .connect_peer(ConnectRequest {
-                id: format!("{}@{}:{}", node_id, host, port),
-                host: None,
-                port: None,
+    /// State of the most advanced channel with `peer_id` that is open or opening.
+    async fn channel_state(&mut self, peer_id: NodeId) -> Result<Option<ChannelState>> {
+        Ok(self
+            .list_funds()
+            .await?
+            .channels
+            .iter()
+            .filter(|ch| ch.peer_id == peer_id.to_vec())
+            .map(|ch| ch.state())
+            .filter(|s| {
+                matches!(
+                    s,
+                    ChannelState::Openingd
+                        | ChannelState::ChanneldAwaitingLockin
+                        | ChannelState::ChanneldNormal
+                )
             })
-            .await?;
-        Ok(())
+            .max_by_key(|s| *s as i32))
     }
 
-    async fn fund_channel(&mut self, node_id: &[u8], amount_sat: u64) -> Result<()> {
-        self.node
-            .fund_channel(FundchannelRequest {
-                id: node_id.to_vec(),
-                amount: Some(AmountOrAll {
-                    value: Some(gl_client::pb::cln::amount_or_all::Value::Amount(
-                        gl_client::pb::cln::Amount {
-                            msat: amount_sat * 1000,
-                        },
-                    )),
-                }),
-                ..Default::default()
-            })
+    #[tracing::instrument(name = "rpc", skip_all, fields(node = self.name, method = "invoice"))]
+    async fn invoice(&mut self, amount: Amount, label: &str) -> error::Result<String> {
+        let request = InvoiceRequest {
+            amount_msat: Some(AmountOrAny {
+                value: Some(gl_client::pb::cln::amount_or_any::Value::Amount(
+                    gl_client::pb::cln::Amount {
+                        msat: amount.to_msat().0,
+                    },
+                )),
+            }),
+            description: label.to_string(),
+            label: label.to_string(),
+            ..Default::default()
+        };
+        // A repeat is refused as a duplicate label instead of creating another
+        // invoice, so a retry whose first attempt got through finds it by label
+        match self
+            .call(
+                "invoice",
+                Idempotency::Safe,
+                request,
+                |mut node, req| async move { node.invoice(req).await },
+            )
+            .await
+        {
+            Ok(invoice) => Ok(invoice.bolt11),
+            Err(Error::Rpc {
+                code: INVOICE_LABEL_ALREADY_EXISTS,
+                ..
+            }) => self.invoice_by_label(label).await,
+            Err(e) => Err(e),
+        }
+    }
+
+    #[tracing::instrument(name = "rpc", skip_all, fields(node = self.name, method = "listinvoices"))]
+    async fn invoice_by_label(&mut self, label: &str) -> error::Result<String> {
+        let request = ListinvoicesRequest {
+            label: Some(label.to_string()),
+            ..Default::default()
+        };
+        self.call(
+            "listinvoices",
+            Idempotency::Safe,
+            request,
+            |mut node, req| async move { node.list_invoices(req).await },
+        )
+        .await?
+        .invoices
+        .into_iter()
+        .find_map(|invoice| invoice.bolt11)
+        .with_context(|| format!("No bolt11 invoice labelled {}", label))
+        .map_err(Error::from)
+    }
+
+    /// Pays `bolt11` and returns what was sent, fees included.
+    #[tracing::instrument(name = "rpc", skip_all, fields(node = self.name, method = "pay"))]
+    async fn pay(&mut self, bolt11: &str) -> error::Result<Amount> {
+        let request = PayRequest {
+            bolt11: bolt11.to_string(),
+            ..Default::default()
+        };
+        let resp = self
+            .call(
+                "pay",
+                Idempotency::Unsafe,
+                request,
+                |mut node, req| async move { node.pay(req).await },
+            )
             .await?;
-        Ok(())
+        if resp.status() != PayStatus::Complete {
+            return Err(anyhow::anyhow!(
+                "Payment {} is {:?}",
+                hex::encode(&resp.payment_hash),
+                resp.status()
+            )
+            .into());
+        }
+        Ok(Amount::from_msat(
+            resp.amount_sent_msat.map(|a| a.msat).unwrap_or(0),
+        ))
     }
 
-    async fn list_peers(&mut self) -> Result<gl_client::pb::cln::ListpeersResponse> {
-        Ok(self
-            .node
-            .list_peers(ListpeersRequest::default())
-            .await?
-            .into_inner())
+    /// Stops the node process and waits until the scheduler has started it
+    /// again and it answers.
+    #[tracing::instrument(skip_all, fields(node = self.name))]
+    async fn restart(&mut self) -> Result<()> {
+        // lightningd exits while answering, so losing the connection is expected
+        match self
+            .call(
+                "stop",
+                Idempotency::Unsafe,
+                StopRequest::default(),
+                |mut node, req| async move { node.stop(req).await },
+            )
+            .await
+        {
+            Ok(_) => {}
+            Err(e) if retry::is_connection_lost(&e) => {}
+            Err(e) => return Err(e.into()),
+        }
+        // Each failed call asks the scheduler again, which starts the node
+        for _ in 0..30 {
+            match self.get_info().await {
+                Ok(_) => return Ok(()),
+                Err(e) => tracing::debug!(error = %e, "Node not back yet"),
+            }
+            recorder::sleep(std::time::Duration::from_secs(1)).await;
+        }
+        Err(Error::Timeout(format!("waiting for {} to restart", self.name)).into())
+    }
+
+    /// Waits until the channel with `peer_id` is in `state`.
+    async fn wait_for_channel(&mut self, peer_id: NodeId, state: ChannelState) -> Result<()> {
+        for _ in 0..60 {
+            if self.channel_state(peer_id).await? == Some(state) {
+                return Ok(());
+            }
+            recorder::sleep(std::time::Duration::from_secs(1)).await;
+        }
+        Err(Error::Timeout(format!(
+            "waiting for the channel with {} to be {:?}",
+            peer_id, state
+        ))
+        .into())
+    }
+
+    /// Funds, channels and peers, once `peer_id` is connected, in an order
+    /// that does not depend on the node.
+    async fn persisted_state(&mut self, peer_id: NodeId) -> Result<PersistedState> {
+        for _ in 0..60 {
+            if self.is_connected(peer_id).await? {
+                let mut funds = self.list_funds().await?;
+                funds
+                    .outputs
+                    .sort_by(|a, b| (&a.txid, a.output).cmp(&(&b.txid, b.output)));
+                funds.channels.sort_by(|a, b| {
+                    (&a.funding_txid, a.funding_output).cmp(&(&b.funding_txid, b.funding_output))
+                });
+                let mut peers: Vec<_> = self
+                    .list_peers()
+                    .await?
+                    .peers
+                    .into_iter()
+                    .map(|p| (hex::encode(&p.id), p.connected, p.num_channels))
+                    .collect();
+                peers.sort();
+                return Ok(PersistedState { funds, peers });
+            }
+            recorder::sleep(std::time::Duration::from_secs(1)).await;
+        }
+        Err(Error::Timeout(format!(
+            "waiting for {} to reconnect to {}",
+            self.name, peer_id
+        ))
+        .into())
+    }
+
+    /// Balances and peers for the metrics endpoint.
+    async fn snapshot(&mut self) -> Result<metrics::NodeSnapshot> {
+        let funds = self.list_funds().await?;
+        let peers = self.list_peers().await?;
+        let mut snapshot = metrics::NodeSnapshot {
+            node: self.name.to_string(),
+            ..Default::default()
+        };
+        for output in &funds.outputs {
+            let status = format!("{:?}", output.status()).to_lowercase();
+            let sat = output
+                .amount_msat
+                .as_ref()
+                .map(|a| a.msat / 1000)
+                .unwrap_or(0);
+            match snapshot.onchain_sat.iter_mut().find(|(s, _)| *s == status) {
+                Some((_, total)) => *total += sat,
+                None => snapshot.onchain_sat.push((status, sat)),
+            }
+        }
+        for ch in &funds.channels {
+            let local_msat = ch.our_amount_msat.as_ref().map(|a| a.msat).unwrap_or(0);
+            let total_msat = ch.amount_msat.as_ref().map(|a| a.msat).unwrap_or(0);
+            snapshot.channels.push(metrics::ChannelBalance {
+                peer: hex::encode(&ch.peer_id),
+                scid: ch.short_channel_id.clone().unwrap_or_default(),
+                local_msat,
+                remote_msat: total_msat.saturating_sub(local_msat),
+            });
+        }
+        for peer in &peers.peers {
+            snapshot.peers.push((hex::encode(&peer.id), peer.connected));
+        }
+        Ok(snapshot)
     }
 }
 
+/// The parts of a node's state that must survive a restart.
+#[derive(Debug, PartialEq)]
+struct PersistedState {
+    funds: gl_client::pb::cln::ListfundsResponse,
+    /// Peer ids, whether each is connected and how many channels it has.
+    peers: Vec<(String, bool, Option<u32>)>,
+}
+
+/// Has `to` invoice `amount` and `from` pay it.
+async fn pay_between(from: &mut GlNode, to: &mut GlNode, amount: Amount) -> Result<()> {
+    let label = recorder::unique_label(&format!("{}-to-{}", from.name, to.name)).await?;
+    let bolt11 = to.invoice(amount, &label).await?;
+    let sent = from.pay(&bolt11).await?;
+    println!(
+        "{} paid {} {} sats, {} sats with fees",
+        from.name, to.name, amount, sent
+    );
+    Ok(())
+}
+
+/// Pays both ways over the Alice -> Bob channel, restarts both nodes through
+/// the scheduler, and checks that funds, channels and peers came back as they
+/// were and that payments still go through both ways.
+async fn run_restart_scenario(
+    alice: &mut GlNode,
+    bob: &mut GlNode,
+    alice_id: NodeId,
+    bob_id: NodeId,
+) -> Result<()> {
+    alice
+        .wait_for_channel(bob_id, ChannelState::ChanneldNormal)
+        .await?;
+
+    println!("\n--- Payments before restart ---");
+    // Alice pays twice as much first, so Bob has more than his reserve to pay back
+    pay_between(alice, bob, PAYMENT_AMOUNT.checked_add(PAYMENT_AMOUNT)?).await?;
+    pay_between(bob, alice, PAYMENT_AMOUNT).await?;
+    // Let the last commitment updates settle on both sides
+    recorder::sleep(std::time::Duration::from_secs(5)).await;
+    let alice_before = alice.persisted_state(bob_id).await?;
+    let bob_before = bob.persisted_state(alice_id).await?;
+
+    println!("\n--- Restarting Alice and Bob ---");
+    alice.restart().await?;
+    bob.restart().await?;
+    let alice_after = alice.persisted_state(bob_id).await?;
+    let bob_after = bob.persisted_state(alice_id).await?;
+    for (name, before, after) in [
+        ("Alice", &alice_before, &alice_after),
+        ("Bob", &bob_before, &bob_after),
+    ] {
+        anyhow::ensure!(
+            before == after,
+            "{}'s state changed across the restart\nbefore: {:#?}\nafter: {:#?}",
+            name,
+            before,
+            after
+        );
+        println!(
+            "{} kept {} outputs, {} channels and {} peers",
+            name,
+            after.funds.outputs.len(),
+            after.funds.channels.len(),
+            after.peers.len()
+        );
+    }
+    alice
+        .wait_for_channel(bob_id, ChannelState::ChanneldNormal)
+        .await?;
+
+    println!("\n--- Payments after restart ---");
+    pay_between(alice, bob, PAYMENT_AMOUNT).await?;
+    pay_between(bob, alice, PAYMENT_AMOUNT).await?;
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config = load_testserver_config()?;
-    println!("Loaded testserver config: {:#?}", config);
+    let _telemetry = telemetry::init("gl-client-tryout")?;
+
+    recorder::init_from_env()?;
 
-    let btc = create_bitcoin_client(&config.bitcoind_rpc_uri)?;
+    // `--metrics <addr>` serves `/metrics` and keeps polling the nodes after setup
+    let metrics_addr = metrics::addr_from_args()?;
+    if let Some(addr) = metrics_addr {
+        anyhow::ensure!(
+            !recorder::replaying(),
+            "--metrics polls live nodes and cannot replay RPC traffic"
+        );
+        metrics::serve(addr)?;
+    }
+
+    // Replay needs neither gltestserver nor bitcoind, only the recording
+    let testserver = if recorder::replaying() {
+        None
+    } else {
+        let config = load_testserver_config()?;
+        println!("Loaded testserver config: {:#?}", config);
+        let nobody_creds = Nobody {
+            cert: fs::read(&config.nobody_crt_path)?,
+            key: fs::read(config.nobody_key_path.expose())?,
+            ca: fs::read(&config.ca_crt_path)?,
+        };
+        Some((config, nobody_creds))
+    };
+
+    let btc = match &testserver {
+        Some((config, _)) => create_bitcoin_client(&config.bitcoind_rpc_uri)?,
+        // Never contacted, every call is answered from the recording
+        None => BitcoinClient::new("http://127.0.0.1:18443", Auth::None)?,
+    };
     println!(
         "Connected to bitcoind, block height: {}",
-        btc.get_block_count()?
+        btc.get_block_count().await?
     );
 
-    let nobody_creds = Nobody {
-        cert: fs::read(&config.nobody_crt_path)?,
-        key: fs::read(&config.nobody_key_path)?,
-        ca: fs::read(&config.ca_crt_path)?,
-    };
-
     println!("\n--- Creating Node Alice ---");
-    let mut alice = GlNode::new(
-        "creds/alice",
-        &config,
-        &nobody_creds,
-        config.scheduler_grpc_uri.clone(),
-    )
-    .await?;
+    let mut alice = match &testserver {
+        Some((config, nobody_creds)) => {
+            GlNode::new(
+                "alice",
+                "creds/alice",
+                &fs::read(&config.ca_crt_path)?,
+                nobody_creds,
+                config.scheduler_grpc_uri.clone(),
+            )
+            .await?
+        }
+        None => GlNode::replayed("alice"),
+    };
     let alice_info = alice.get_info().await?;
-    println!("Alice node_id: {}", hex::encode(&alice_info.id));
+    let alice_id = NodeId::from_slice(&alice_info.id)?;
+    println!("Alice node_id: {}", alice_id);
     println!("Alice binding: {:?}", alice_info.binding);
 
     println!("\n--- Creating Node Bob ---");
-    let mut bob = GlNode::new(
-        "creds/bob",
-        &config,
-        &nobody_creds,
-        config.scheduler_grpc_uri.clone(),
-    )
-    .await?;
+    let mut bob = match &testserver {
+        Some((config, nobody_creds)) => {
+            GlNode::new(
+                "bob",
+                "creds/bob",
+                &fs::read(&config.ca_crt_path)?,
+                nobody_creds,
+                config.scheduler_grpc_uri.clone(),
+            )
+            .await?
+        }
+        None => GlNode::replayed("bob"),
+    };
     let bob_info = bob.get_info().await?;
-    println!("Bob node_id: {}", hex::encode(&bob_info.id));
+    let bob_id = NodeId::from_slice(&bob_info.id)?;
+    println!("Bob node_id: {}", bob_id);
     println!("Bob binding: {:?}", bob_info.binding);
     println!("Bob address: {:?}", bob_info.address);
 
+    let mut report = SetupReport::default();
+    let channel = alice.channel_state(bob_id).await?;
+
     println!("\n--- Funding Alice ---");
     let alice_addr = alice.new_address().await?;
     println!("Alice address: {}", alice_addr);
-
-    // Mine blocks to fund Alice
     let alice_btc_addr = bitcoincore_rpc::bitcoin::Address::from_str(&alice_addr)?.assume_checked();
-    btc.generate_to_address(101, &alice_btc_addr)?;
-    println!("Mined 101 blocks to Alice's address");
 
-    tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+    let balance = alice.confirmed_balance().await?;
+    if channel.is_some() || balance >= CHANNEL_AMOUNT.checked_add(FUNDING_FEE_RESERVE)? {
+        report.reused(format!("Alice's confirmed balance of {} sats", balance));
+    } else {
+        // Mine blocks to fund Alice
+        btc.generate_to_address(101, &alice_btc_addr).await?;
+        println!("Mined 101 blocks to Alice's address");
 
-    let alice_funds = alice.list_funds().await?;
-    let total_sats: u64 = alice_funds
-        .outputs
-        .iter()
-        .map(|o| o.amount_msat.as_ref().map(|a| a.msat / 1000).unwrap_or(0))
-        .sum();
-    println!("Alice confirmed balance: {} sats", total_sats);
+        recorder::sleep(std::time::Duration::from_secs(30)).await;
+
+        let balance = alice.confirmed_balance().await?;
+        println!("Alice confirmed balance: {} sats", balance);
+        report.created(format!("101 blocks of funds for Alice ({} sats)", balance));
+    }
 
     println!("\n--- Connecting Alice to Bob ---");
-    let bob_binding = bob_info.binding.first().context("Bob has no binding")?;
-    let bob_p2p_port = bob_binding.port.context("Bob binding has no port")?;
-    alice
-        .connect_peer(
-            &hex::encode(&bob_info.id),
+    if alice.is_connected(bob_id).await? {
+        report.reused("connection Alice -> Bob".to_string());
+    } else {
+        let bob_binding = bob_info.binding.first().context("Bob has no binding")?;
+        let bob_addr = PeerAddress::new(
+            bob_id,
             bob_binding.address.as_deref().unwrap_or("127.0.0.1"),
-            bob_p2p_port,
-        )
-        .await?;
-    println!("Alice connected to Bob");
+            bob_binding
+                .port
+                .context("Bob binding has no port")?
+                .try_into()?,
+        );
+        alice.connect_peer(&bob_addr).await?;
+        report.created("connection Alice -> Bob".to_string());
+    }
 
     println!("\n--- Opening Channel: Alice -> Bob (100,000 sats) ---");
-    alice.fund_channel(&bob_info.id, 100_000).await?;
-    println!("Channel funding initiated");
+    match channel {
+        Some(ChannelState::ChanneldNormal) => report.reused("channel Alice -> Bob".to_string()),
+        Some(state) => {
+            btc.generate_to_address(6, &alice_btc_addr).await?;
+            println!("Mined 6 blocks to confirm channel");
+            report.reused(format!("channel Alice -> Bob found in {:?}", state));
+        }
+        None => {
+            alice.fund_channel(bob_id, CHANNEL_AMOUNT).await?;
+            println!("Channel funding initiated");
 
-    btc.generate_to_address(6, &alice_btc_addr)?;
-    println!("Mined 6 blocks to confirm channel");
+            btc.generate_to_address(6, &alice_btc_addr).await?;
+            println!("Mined 6 blocks to confirm channel");
+            report.created(format!("channel Alice -> Bob of {} sats", CHANNEL_AMOUNT));
+        }
+    }
 
     println!("\n--- Verifying Channel ---");
 
@@ -317,6 +904,278 @@ async fn main() -> Result<()> {
         );
     }
 
+    println!("\n--- Setup ---");
+    report.print();
+
+    run_restart_scenario(&mut alice, &mut bob, alice_id, bob_id).await?;
+
+    println!("\n--- Node calls ---");
+    alice.print_call_stats();
+    bob.print_call_stats();
+
     println!("\n=== Test Complete ===");
+
+    if metrics_addr.is_some() {
+        loop {
+            match (alice.snapshot().await, bob.snapshot().await) {
+                (Ok(a), Ok(b)) => metrics::update_nodes(&[a, b]),
+                (Err(e), _) | (_, Err(e)) => tracing::warn!(
+                    error = %format!("{:#}", e),
+                    "Reading node state for metrics failed"
+                ),
+            }
+            tokio::time::sleep(METRICS_POLL_INTERVAL).await;
+        }
+    }
     Ok(())
 }
+
+/// `GlNode` against the in-process mock scheduler and node.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock_greenlight::{Fault, MockGreenlight, REGISTER, SCHEDULE};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    const GETINFO: &str = "/cln.Node/Getinfo";
+    const FUNDCHANNEL: &str = "/cln.Node/FundChannel";
+    const INVOICE: &str = "/cln.Node/Invoice";
+
+    /// A mock with a scripted `getinfo` and a creds directory of its own,
+    /// removed when the test ends.
+    struct Fixture {
+        mock: MockGreenlight,
+        dir: PathBuf,
+        node_id: NodeId,
+    }
+
+    impl Fixture {
+        async fn new(test: &str) -> Self {
+            let mock = MockGreenlight::start().await.unwrap();
+            // The secp256k1 generator, a valid key that no real node uses
+            let node_id: NodeId =
+                "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                    .parse()
+                    .unwrap();
+            mock.respond(
+                "Getinfo",
+                gl_client::pb::cln::GetinfoResponse {
+                    id: node_id.to_vec(),
+                    alias: Some("mock".to_string()),
+                    ..Default::default()
+                },
+            );
+            let dir = std::env::temp_dir().join(format!("gl-mock-{}-{}", std::process::id(), test));
+            Fixture { mock, dir, node_id }
+        }
+
+        async fn start(&self) -> Result<GlNode> {
+            GlNode::new(
+                "mock",
+                self.dir.to_str().context("temp dir is not UTF-8")?,
+                &self.mock.ca_pem,
+                &self.mock.nobody,
+                self.mock.uri.clone(),
+            )
+            .await
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn registers_once_and_reuses_persisted_creds() {
+        let f = Fixture::new("creds").await;
+        let mut node = f.start().await.unwrap();
+        assert_eq!(node.get_info().await.unwrap().id, f.node_id.to_vec());
+        assert_eq!(f.mock.calls(REGISTER), 1);
+        drop(node);
+
+        let delay = Duration::from_millis(500);
+        f.mock.inject(SCHEDULE, Fault::Delay(delay));
+        let started = std::time::Instant::now();
+        f.start().await.unwrap().get_info().await.unwrap();
+        assert_eq!(f.mock.calls(REGISTER), 1, "node registered again");
+        assert!(started.elapsed() >= delay, "schedule was not delayed");
+    }
+
+    #[tokio::test]
+    async fn scheduler_failures_surface_as_errors() {
+        let f = Fixture::new("scheduler").await;
+        f.start().await.unwrap();
+
+        f.mock.inject(SCHEDULE, Fault::unavailable());
+        assert!(f.start().await.is_err(), "node started without a scheduler");
+
+        fs::remove_file(f.dir.join(CREDS_FILE_NAME)).unwrap();
+        f.mock.inject(REGISTER, Fault::already_registered());
+        let e = f.start().await.err().expect("node registered twice");
+        assert!(format!("{:#}", e).contains("already registered"), "{:#}", e);
+    }
+
+    #[tokio::test]
+    async fn node_errors_are_typed() {
+        let f = Fixture::new("errors").await;
+        let mut node = f.start().await.unwrap();
+        f.mock.inject(
+            FUNDCHANNEL,
+            Fault::Fail(
+                tonic::Code::Unknown,
+                "Error calling method FundChannel: RpcError { code: Some(300), message: \"Could not afford 100000sat\", data: None }".to_string(),
+            ),
+        );
+        let e = node
+            .fund_channel(f.node_id, CHANNEL_AMOUNT)
+            .await
+            .unwrap_err();
+        assert!(matches!(e, Error::InsufficientFunds(_)), "{}", e);
+
+        let e = node.list_peers().await.unwrap_err();
+        assert!(
+            matches!(
+                e,
+                Error::Grpc {
+                    code: tonic::Code::Unimplemented,
+                    ..
+                }
+            ),
+            "{}",
+            e
+        );
+    }
+
+    #[tokio::test]
+    async fn safe_calls_are_retried() {
+        let f = Fixture::new("retries").await;
+        let mut node = f.start().await.unwrap();
+        let before = f.mock.calls(GETINFO);
+        f.mock.inject(GETINFO, Fault::unavailable());
+        f.mock.inject(GETINFO, Fault::unavailable());
+        node.get_info().await.unwrap();
+        assert_eq!(f.mock.calls(GETINFO), before + 3);
+    }
+
+    #[tokio::test]
+    async fn stalled_calls_are_retried_after_their_deadline() {
+        let f = Fixture::new("deadline").await;
+        let mut node = f.start().await.unwrap();
+        node.set_policy(
+            "getinfo",
+            RetryPolicy {
+                deadline: Duration::from_millis(200),
+                ..RetryPolicy::SAFE
+            },
+        );
+        f.mock.inject(GETINFO, Fault::Delay(Duration::from_secs(2)));
+        node.get_info().await.unwrap();
+        assert_eq!(node.call_stats()["getinfo"].retries, 1);
+    }
+
+    #[tokio::test]
+    async fn unsafe_calls_are_not_retried() {
+        let f = Fixture::new("unsafe").await;
+        let mut node = f.start().await.unwrap();
+        let before = f.mock.calls(FUNDCHANNEL);
+        f.mock.inject(FUNDCHANNEL, Fault::unavailable());
+        node.fund_channel(f.node_id, CHANNEL_AMOUNT)
+            .await
+            .expect_err("channel funded on an unavailable node");
+        assert_eq!(f.mock.calls(FUNDCHANNEL), before + 1);
+    }
+
+    #[tokio::test]
+    async fn rescheduled_node_is_found_again() {
+        let f = Fixture::new("reschedule").await;
+        let mut node = f.start().await.unwrap();
+        let old_uri = f.mock.node_uri();
+        f.mock.restart_node().await.unwrap();
+        assert_ne!(f.mock.node_uri(), old_uri);
+        let schedules = f.mock.calls(SCHEDULE);
+        node.get_info().await.unwrap();
+        assert_eq!(f.mock.calls(SCHEDULE), schedules + 1);
+        assert_eq!(node.call_stats()["getinfo"].reconnects, 1);
+
+        // A call that moves funds is not sent again on the new connection
+        f.mock.restart_node().await.unwrap();
+        let before = f.mock.calls(FUNDCHANNEL);
+        node.fund_channel(f.node_id, CHANNEL_AMOUNT)
+            .await
+            .expect_err("channel funded on a node that went away");
+        assert_eq!(f.mock.calls(FUNDCHANNEL), before);
+        node.get_info().await.unwrap();
+        assert_eq!(
+            f.mock.calls(SCHEDULE),
+            schedules + 2,
+            "reconnected node was not kept"
+        );
+    }
+
+    #[tokio::test]
+    async fn payments_and_restart_report_their_outcome() {
+        let f = Fixture::new("payments").await;
+        let mut node = f.start().await.unwrap();
+        f.mock.respond(
+            "Invoice",
+            gl_client::pb::cln::InvoiceResponse {
+                bolt11: "lnbcrt50u1mock".to_string(),
+                ..Default::default()
+            },
+        );
+        f.mock.respond(
+            "Pay",
+            gl_client::pb::cln::PayResponse {
+                amount_sent_msat: Some(gl_client::pb::cln::Amount { msat: 5_001_000 }),
+                status: PayStatus::Complete as i32,
+                ..Default::default()
+            },
+        );
+        let bolt11 = node.invoice(PAYMENT_AMOUNT, "mock").await.unwrap();
+
+        // The first attempt's invoice is looked up instead of failing the retry
+        f.mock.inject(INVOICE, Fault::unavailable());
+        f.mock.inject(
+            INVOICE,
+            Fault::Fail(
+                tonic::Code::Unknown,
+                "Error calling method Invoice: RpcError { code: Some(900), message: \"Duplicate label 'mock'\", data: None }".to_string(),
+            ),
+        );
+        f.mock.respond(
+            "ListInvoices",
+            gl_client::pb::cln::ListinvoicesResponse {
+                invoices: vec![gl_client::pb::cln::ListinvoicesInvoices {
+                    label: "mock".to_string(),
+                    bolt11: Some(bolt11.clone()),
+                    ..Default::default()
+                }],
+            },
+        );
+        assert_eq!(node.invoice(PAYMENT_AMOUNT, "mock").await.unwrap(), bolt11);
+        assert_eq!(
+            node.pay(&bolt11).await.unwrap(),
+            Amount::from_msat(5_001_000)
+        );
+
+        f.mock.respond(
+            "Pay",
+            gl_client::pb::cln::PayResponse {
+                payment_hash: vec![1; 32],
+                status: PayStatus::Pending as i32,
+                ..Default::default()
+            },
+        );
+        node.pay(&bolt11)
+            .await
+            .expect_err("pending payment reported as complete");
+
+        f.mock
+            .respond("Stop", gl_client::pb::cln::StopResponse::default());
+        node.restart().await.unwrap();
+        assert_eq!(f.mock.calls("/cln.Node/Stop"), 1);
+    }
+}
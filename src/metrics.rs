@@ -0,0 +1,241 @@
+//! Prometheus metrics for node, channel, signer and swap state, served as text
+//! on `/metrics` by the long-running modes of both binaries.
+
+use anyhow::Result;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, StatusCode};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+struct Metrics {
+    registry: Registry,
+    onchain_balance_sat: IntGaugeVec,
+    channel_local_balance_msat: IntGaugeVec,
+    channel_remote_balance_msat: IntGaugeVec,
+    peer_connected: IntGaugeVec,
+    signer_up: IntGaugeVec,
+    swaps: IntCounterVec,
+    swap_duration_seconds: HistogramVec,
+    swap_premium_sat: HistogramVec,
+    swap_onchain_fee_sat: HistogramVec,
+}
+
+static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new().expect("metric definitions are valid"));
+
+impl Metrics {
+    fn new() -> Result<Self> {
+        let registry = Registry::new();
+        let gauge = |name: &str, help: &str, labels: &[&str]| -> Result<IntGaugeVec> {
+            let gauge = IntGaugeVec::new(Opts::new(name, help), labels)?;
+            registry.register(Box::new(gauge.clone()))?;
+            Ok(gauge)
+        };
+        let histogram = |name: &str, help: &str, buckets: Vec<f64>| -> Result<HistogramVec> {
+            let histogram = HistogramVec::new(
+                HistogramOpts::new(name, help).buckets(buckets),
+                &["asset", "direction"],
+            )?;
+            registry.register(Box::new(histogram.clone()))?;
+            Ok(histogram)
+        };
+        let sat_buckets = vec![
+            -10_000.0,
+            -1_000.0,
+            0.0,
+            100.0,
+            1_000.0,
+            10_000.0,
+            100_000.0,
+            1_000_000.0,
+        ];
+
+        let swaps = IntCounterVec::new(
+            Opts::new("swaps_total", "Swaps run, by outcome"),
+            &["asset", "direction", "outcome"],
+        )?;
+        registry.register(Box::new(swaps.clone()))?;
+
+        Ok(Metrics {
+            onchain_balance_sat: gauge(
+                "onchain_balance_sat",
+                "Sum of the node's list_funds outputs, by output status",
+                &["node", "status"],
+            )?,
+            channel_local_balance_msat: gauge(
+                "channel_local_balance_msat",
+                "Our side of the channel (our_amount_msat)",
+                &["node", "peer", "scid"],
+            )?,
+            channel_remote_balance_msat: gauge(
+                "channel_remote_balance_msat",
+                "The peer's side of the channel",
+                &["node", "peer", "scid"],
+            )?,
+            peer_connected: gauge(
+                "peer_connected",
+                "1 if list_peers reports the peer as connected",
+                &["node", "peer"],
+            )?,
+            signer_up: gauge(
+                "signer_up",
+                "1 while the node's signer task is running",
+                &["node"],
+            )?,
+            swaps,
+            swap_duration_seconds: histogram(
+                "swap_duration_seconds",
+                "Time from starting a swap until it completed or failed",
+                prometheus::exponential_buckets(5.0, 2.0, 8)?,
+            )?,
+            swap_premium_sat: histogram(
+                "swap_premium_sat",
+                "Premium of completed swaps; negative when the peer paid it",
+                sat_buckets.clone(),
+            )?,
+            swap_onchain_fee_sat: histogram(
+                "swap_onchain_fee_sat",
+                "Opening transaction fee of completed swaps",
+                sat_buckets,
+            )?,
+            registry,
+        })
+    }
+}
+
+/// One node's balances and peers, as read from `list_funds` and `list_peers`.
+#[derive(Default)]
+pub struct NodeSnapshot {
+    pub node: String,
+    /// On-chain sats per output status, e.g. `confirmed`.
+    pub onchain_sat: Vec<(String, u64)>,
+    pub channels: Vec<ChannelBalance>,
+    /// Peer ids and whether each is connected.
+    pub peers: Vec<(String, bool)>,
+}
+
+pub struct ChannelBalance {
+    pub peer: String,
+    pub scid: String,
+    pub local_msat: u64,
+    pub remote_msat: u64,
+}
+
+/// Replaces the node gauges with `snapshots`, dropping channels and peers that
+/// are gone.
+pub fn update_nodes(snapshots: &[NodeSnapshot]) {
+    let m = &*METRICS;
+    m.onchain_balance_sat.reset();
+    m.channel_local_balance_msat.reset();
+    m.channel_remote_balance_msat.reset();
+    m.peer_connected.reset();
+    for s in snapshots {
+        for (status, sat) in &s.onchain_sat {
+            m.onchain_balance_sat
+                .with_label_values(&[&s.node, status])
+                .add(*sat as i64);
+        }
+        for ch in &s.channels {
+            let labels = [s.node.as_str(), &ch.peer, &ch.scid];
+            m.channel_local_balance_msat
+                .with_label_values(&labels)
+                .set(ch.local_msat as i64);
+            m.channel_remote_balance_msat
+                .with_label_values(&labels)
+                .set(ch.remote_msat as i64);
+        }
+        for (peer, connected) in &s.peers {
+            m.peer_connected
+                .with_label_values(&[&s.node, peer])
+                .set(*connected as i64);
+        }
+    }
+}
+
+pub fn set_signer_up(node: &str, up: bool) {
+    METRICS.signer_up.with_label_values(&[node]).set(up as i64);
+}
+
+/// Counts a swap that ran for `elapsed`. `completed` carries its premium and
+/// on-chain fee in sats, or is `None` if the swap failed.
+pub fn record_swap(asset: &str, direction: &str, elapsed: Duration, completed: Option<(i64, u64)>) {
+    let m = &*METRICS;
+    let outcome = if completed.is_some() {
+        "completed"
+    } else {
+        "failed"
+    };
+    m.swaps
+        .with_label_values(&[asset, direction, outcome])
+        .inc();
+    m.swap_duration_seconds
+        .with_label_values(&[asset, direction])
+        .observe(elapsed.as_secs_f64());
+    if let Some((premium, fee)) = completed {
+        m.swap_premium_sat
+            .with_label_values(&[asset, direction])
+            .observe(premium as f64);
+        m.swap_onchain_fee_sat
+            .with_label_values(&[asset, direction])
+            .observe(fee as f64);
+    }
+}
+
+fn render() -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    TextEncoder::new().encode(&METRICS.registry.gather(), &mut buf)?;
+    Ok(buf)
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&hyper::Method::GET, "/metrics") => match render() {
+            Ok(body) => Response::builder()
+                .header(
+                    hyper::header::CONTENT_TYPE,
+                    TextEncoder::new().format_type(),
+                )
+                .body(Body::from(body)),
+            Err(e) => Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(format!("{:#}", e))),
+        },
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.expect("static response parts are valid"))
+}
+
+/// Binds `addr` and serves `/metrics` on it in the background.
+pub fn serve(addr: SocketAddr) -> Result<()> {
+    let server = hyper::Server::try_bind(&addr)?.serve(make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(handle))
+    }));
+    tracing::info!(addr = %server.local_addr(), "Serving metrics");
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            tracing::error!(error = ?e, "Metrics server stopped");
+        }
+    });
+    Ok(())
+}
+
+/// `--metrics <addr>` from the command line, if given.
+pub fn addr_from_args() -> Result<Option<SocketAddr>> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.iter().position(|a| a == "--metrics") {
+        Some(i) => {
+            let addr = args.get(i + 1).ok_or_else(|| {
+                anyhow::anyhow!("--metrics needs an address, e.g. 127.0.0.1:9184")
+            })?;
+            Ok(Some(addr.parse()?))
+        }
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,439 @@
+//! In-process stand-in for the Greenlight scheduler and a node, served over
+//! TLS with tonic so `GlNode` runs against it unchanged.
+//!
+//! The scheduler handles challenges, registration, recovery and scheduling
+//! itself. Node RPCs answer with whatever response was scripted for them, and
+//! any method can be made to fail or stall on its next call. The node runs on
+//! a port of its own and can be restarted on another one, as Greenlight does
+//! when it reschedules a node.
+
+use anyhow::Result;
+use gl_client::credentials::Nobody;
+use gl_client::pb::scheduler::{
+    ChallengeResponse, NodeInfoRequest, NodeInfoResponse, RecoveryRequest, RecoveryResponse,
+    RegistrationRequest, RegistrationResponse, ScheduleRequest, UpgradeResponse,
+};
+use prost::Message;
+use prost::bytes::{Buf, BufMut};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::codegen::{Body, BoxFuture, Context, Poll, Service, StdError, http};
+use tonic::server::{Grpc, NamedService, UnaryService};
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+use tonic::{Code, Status};
+
+pub const REGISTER: &str = "/scheduler.Scheduler/Register";
+pub const RECOVER: &str = "/scheduler.Scheduler/Recover";
+pub const SCHEDULE: &str = "/scheduler.Scheduler/Schedule";
+pub const GET_NODE_INFO: &str = "/scheduler.Scheduler/GetNodeInfo";
+const GET_CHALLENGE: &str = "/scheduler.Scheduler/GetChallenge";
+const MAYBE_UPGRADE: &str = "/scheduler.Scheduler/MaybeUpgrade";
+const SIGNER_REQUESTS_STREAM: &str = "/scheduler.Scheduler/SignerRequestsStream";
+
+/// What the next call of a method does instead of, or before, answering.
+pub enum Fault {
+    /// Fails with `code` and `message`, e.g. `Code::Unavailable`.
+    Fail(Code, String),
+    /// Answers normally after `Duration`.
+    Delay(Duration),
+}
+
+impl Fault {
+    pub fn unavailable() -> Self {
+        Fault::Fail(Code::Unavailable, "scheduler unavailable".to_string())
+    }
+
+    pub fn already_registered() -> Self {
+        Fault::Fail(Code::AlreadyExists, "node already registered".to_string())
+    }
+}
+
+struct State {
+    ca: Certificate,
+    node_uri: Mutex<String>,
+    registered: Mutex<HashSet<Vec<u8>>>,
+    /// Encoded responses of node RPCs, by gRPC path.
+    responses: Mutex<HashMap<String, Vec<u8>>>,
+    faults: Mutex<HashMap<String, VecDeque<Fault>>>,
+    calls: Mutex<HashMap<String, usize>>,
+}
+
+pub struct MockGreenlight {
+    /// URI of the scheduler.
+    pub uri: String,
+    pub ca_pem: Vec<u8>,
+    pub nobody: Nobody,
+    state: Arc<State>,
+    identity: Identity,
+    _scheduler: Served,
+    node: Mutex<Served>,
+}
+
+/// A running server, stopped when this is dropped or `stop` is called.
+struct Served {
+    shutdown: oneshot::Sender<()>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Served {
+    /// Stops accepting calls and waits until open connections are closed.
+    async fn stop(self) {
+        let _ = self.shutdown.send(());
+        let _ = self.task.await;
+    }
+}
+
+impl MockGreenlight {
+    /// Serves the mock on a free localhost port until it is dropped.
+    pub async fn start() -> Result<Self> {
+        let mut ca_params = CertificateParams::new(vec![]);
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "mock greenlight CA");
+        let ca = Certificate::from_params(ca_params)?;
+        let ca_pem = ca.serialize_pem()?.into_bytes();
+
+        let server = Certificate::from_params(CertificateParams::new(vec!["localhost".into()]))?;
+        let identity = Identity::from_pem(
+            server.serialize_pem_with_signer(&ca)?,
+            server.serialize_private_key_pem(),
+        );
+
+        let mut nobody_params = CertificateParams::new(vec!["localhost".into()]);
+        nobody_params
+            .distinguished_name
+            .push(DnType::CommonName, "/users/nobody");
+        let nobody_cert = Certificate::from_params(nobody_params)?;
+        let nobody = Nobody {
+            cert: nobody_cert.serialize_pem_with_signer(&ca)?.into_bytes(),
+            key: nobody_cert.serialize_private_key_pem().into_bytes(),
+            ca: ca_pem.clone(),
+        };
+
+        let state = Arc::new(State {
+            ca,
+            node_uri: Default::default(),
+            registered: Default::default(),
+            responses: Default::default(),
+            faults: Default::default(),
+            calls: Default::default(),
+        });
+        let (uri, scheduler) =
+            serve(&identity, MockService::<SchedulerName>::new(state.clone())).await?;
+        let (node_uri, node) =
+            serve(&identity, MockService::<ClnNodeName>::new(state.clone())).await?;
+        *state.node_uri.lock().unwrap() = node_uri;
+
+        Ok(MockGreenlight {
+            uri,
+            ca_pem,
+            nobody,
+            state,
+            identity,
+            _scheduler: scheduler,
+            node: Mutex::new(node),
+        })
+    }
+
+    /// Stops the node and starts it again on another port, which the
+    /// scheduler hands out from then on.
+    pub async fn restart_node(&self) -> Result<()> {
+        let (node_uri, node) = serve(
+            &self.identity,
+            MockService::<ClnNodeName>::new(self.state.clone()),
+        )
+        .await?;
+        let old = std::mem::replace(&mut *self.node.lock().unwrap(), node);
+        old.stop().await;
+        *self.state.node_uri.lock().unwrap() = node_uri;
+        Ok(())
+    }
+
+    /// Where the scheduler currently says the node runs.
+    pub fn node_uri(&self) -> String {
+        self.state.node_uri.lock().unwrap().clone()
+    }
+
+    /// Makes the node answer `method`, e.g. `Getinfo`, with `response`.
+    pub fn respond<M: Message>(&self, method: &str, response: M) {
+        self.state
+            .responses
+            .lock()
+            .unwrap()
+            .insert(format!("/cln.Node/{}", method), response.encode_to_vec());
+    }
+
+    /// Applies `fault` to the next call of `path`, after any queued before it.
+    pub fn inject(&self, path: &str, fault: Fault) {
+        self.state
+            .faults
+            .lock()
+            .unwrap()
+            .entry(path.to_string())
+            .or_default()
+            .push_back(fault);
+    }
+
+    /// How often `path` was called, faulted calls included.
+    pub fn calls(&self, path: &str) -> usize {
+        self.state
+            .calls
+            .lock()
+            .unwrap()
+            .get(path)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+impl State {
+    async fn handle(&self, path: &str, request: Vec<u8>) -> Result<Vec<u8>, Status> {
+        *self
+            .calls
+            .lock()
+            .unwrap()
+            .entry(path.to_string())
+            .or_default() += 1;
+        let fault = self
+            .faults
+            .lock()
+            .unwrap()
+            .get_mut(path)
+            .and_then(VecDeque::pop_front);
+        match fault {
+            Some(Fault::Fail(code, message)) => return Err(Status::new(code, message)),
+            Some(Fault::Delay(delay)) => tokio::time::sleep(delay).await,
+            None => {}
+        }
+
+        match path {
+            GET_CHALLENGE => Ok(ChallengeResponse {
+                challenge: rand::random::<[u8; 32]>().to_vec(),
+            }
+            .encode_to_vec()),
+            REGISTER => {
+                let req = RegistrationRequest::decode(request.as_slice()).map_err(decode_error)?;
+                if !self.registered.lock().unwrap().insert(req.node_id) {
+                    return Err(Status::already_exists("node already registered"));
+                }
+                Ok(RegistrationResponse {
+                    device_cert: self
+                        .sign_csr(&req.csr)
+                        .map_err(|e| Status::invalid_argument(e.to_string()))?,
+                    ..Default::default()
+                }
+                .encode_to_vec())
+            }
+            RECOVER => {
+                let req = RecoveryRequest::decode(request.as_slice()).map_err(decode_error)?;
+                if !self.registered.lock().unwrap().contains(&req.node_id) {
+                    return Err(Status::not_found("node is not registered"));
+                }
+                Ok(RecoveryResponse {
+                    device_cert: self
+                        .sign_csr(&req.csr)
+                        .map_err(|e| Status::invalid_argument(e.to_string()))?,
+                    ..Default::default()
+                }
+                .encode_to_vec())
+            }
+            SCHEDULE => {
+                let req = ScheduleRequest::decode(request.as_slice()).map_err(decode_error)?;
+                Ok(self.node_info(req.node_id, true).encode_to_vec())
+            }
+            GET_NODE_INFO => {
+                // The signer waits for the node to be scheduled; an empty URI
+                // makes it back off instead of streaming HSM requests we can't serve
+                let req = NodeInfoRequest::decode(request.as_slice()).map_err(decode_error)?;
+                Ok(self.node_info(req.node_id, !req.wait).encode_to_vec())
+            }
+            MAYBE_UPGRADE => Ok(UpgradeResponse::default().encode_to_vec()),
+            SIGNER_REQUESTS_STREAM => std::future::pending().await,
+            _ => self
+                .responses
+                .lock()
+                .unwrap()
+                .get(path)
+                .cloned()
+                .ok_or_else(|| Status::unimplemented(format!("{} is not scripted", path))),
+        }
+    }
+
+    fn node_info(&self, node_id: Vec<u8>, scheduled: bool) -> NodeInfoResponse {
+        NodeInfoResponse {
+            node_id,
+            grpc_uri: if scheduled {
+                self.node_uri.lock().unwrap().clone()
+            } else {
+                String::new()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Issues the device certificate, keeping the subject the client chose.
+    fn sign_csr(&self, csr: &[u8]) -> Result<String> {
+        let csr = rcgen::CertificateSigningRequest::from_pem(std::str::from_utf8(csr)?)?;
+        Ok(csr.serialize_pem_with_signer(&self.ca)?)
+    }
+}
+
+/// Serves `service` on a free localhost port.
+async fn serve<N: ServiceName + Send + 'static>(
+    identity: &Identity,
+    service: MockService<N>,
+) -> Result<(String, Served)> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let uri = format!("https://localhost:{}", listener.local_addr()?.port());
+    let incoming =
+        TcpIncoming::from_listener(listener, true, None).map_err(|e| anyhow::anyhow!("{}", e))?;
+    let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+    let router = Server::builder()
+        .tls_config(ServerTlsConfig::new().identity(identity.clone()))?
+        .add_service(service);
+    let task = tokio::spawn(async move {
+        if let Err(e) = router
+            .serve_with_incoming_shutdown(incoming, async {
+                let _ = shutdown_rx.await;
+            })
+            .await
+        {
+            tracing::error!(error = ?e, "Mock Greenlight stopped");
+        }
+    });
+    Ok((uri, Served { shutdown, task }))
+}
+
+fn decode_error(e: prost::DecodeError) -> Status {
+    Status::invalid_argument(e.to_string())
+}
+
+/// gRPC codec that leaves messages encoded, so one service can route any path.
+#[derive(Default)]
+struct RawCodec;
+
+impl Codec for RawCodec {
+    type Encode = Vec<u8>;
+    type Decode = Vec<u8>;
+    type Encoder = RawCodec;
+    type Decoder = RawCodec;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        RawCodec
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        RawCodec
+    }
+}
+
+impl Encoder for RawCodec {
+    type Item = Vec<u8>;
+    type Error = Status;
+
+    fn encode(&mut self, item: Vec<u8>, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        dst.put_slice(&item);
+        Ok(())
+    }
+}
+
+impl Decoder for RawCodec {
+    type Item = Vec<u8>;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<Vec<u8>>, Status> {
+        Ok(Some(src.copy_to_bytes(src.remaining()).to_vec()))
+    }
+}
+
+struct Unary {
+    state: Arc<State>,
+    path: String,
+}
+
+impl UnaryService<Vec<u8>> for Unary {
+    type Response = Vec<u8>;
+    type Future = BoxFuture<tonic::Response<Vec<u8>>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Vec<u8>>) -> Self::Future {
+        let state = self.state.clone();
+        let path = self.path.clone();
+        Box::pin(async move {
+            state
+                .handle(&path, request.into_inner())
+                .await
+                .map(tonic::Response::new)
+        })
+    }
+}
+
+trait ServiceName {
+    const NAME: &'static str;
+}
+
+enum SchedulerName {}
+
+impl ServiceName for SchedulerName {
+    const NAME: &'static str = "scheduler.Scheduler";
+}
+
+enum ClnNodeName {}
+
+impl ServiceName for ClnNodeName {
+    const NAME: &'static str = "cln.Node";
+}
+
+/// Every method of the gRPC service `N`, answered by `State::handle`.
+struct MockService<N> {
+    state: Arc<State>,
+    _name: PhantomData<N>,
+}
+
+impl<N> MockService<N> {
+    fn new(state: Arc<State>) -> Self {
+        MockService {
+            state,
+            _name: PhantomData,
+        }
+    }
+}
+
+impl<N> Clone for MockService<N> {
+    fn clone(&self) -> Self {
+        MockService::new(self.state.clone())
+    }
+}
+
+impl<N: ServiceName> NamedService for MockService<N> {
+    const NAME: &'static str = N::NAME;
+}
+
+impl<N, B> Service<http::Request<B>> for MockService<N>
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let method = Unary {
+            state: self.state.clone(),
+            path: req.uri().path().to_string(),
+        };
+        Box::pin(async move { Ok(Grpc::new(RawCodec).unary(method, req).await) })
+    }
+}
@@ -0,0 +1,251 @@
+//! Record and replay of RPC traffic, so scenario logic and balance checks can
+//! run offline against a previous run.
+//!
+//! `RPC_RECORD=<file>` appends every RPC request/response pair to `<file>` as
+//! JSONL. `RPC_REPLAY=<file>` answers RPCs from such a file instead of the
+//! nodes, and makes waits between polls return immediately.
+
+use crate::error::Error;
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::future::Future;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// One RPC as it appears on a line of the recording.
+#[derive(Serialize, Deserialize)]
+struct Exchange {
+    /// Which daemon answered, e.g. `bitcoind` or `lightning-cli:alice`.
+    service: String,
+    method: String,
+    request: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// The `Error` variant the call failed with, so a replay fails the same way.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error_kind: Option<Value>,
+}
+
+enum Mode {
+    Record(Mutex<File>),
+    /// Recorded exchanges by service and method, in recording order.
+    Replay(Mutex<HashMap<(String, String), VecDeque<Exchange>>>),
+}
+
+static MODE: OnceLock<Mode> = OnceLock::new();
+
+impl Mode {
+    /// Appends exchanges to `path`.
+    fn record(path: &str) -> Result<Self> {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening {}", path))?;
+        Ok(Mode::Record(Mutex::new(file)))
+    }
+
+    /// Answers from the exchanges recorded in `path`.
+    fn replay(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("opening {}", path))?;
+        let mut recorded: HashMap<_, VecDeque<_>> = HashMap::new();
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let exchange: Exchange =
+                serde_json::from_str(&line).with_context(|| format!("{} line {}", path, n + 1))?;
+            recorded
+                .entry((exchange.service.clone(), exchange.method.clone()))
+                .or_default()
+                .push_back(exchange);
+        }
+        Ok(Mode::Replay(Mutex::new(recorded)))
+    }
+
+    async fn call<Req, Resp>(
+        &self,
+        service: &str,
+        method: &str,
+        request: &Req,
+        rpc: impl Future<Output = Result<Resp>>,
+    ) -> Result<Resp>
+    where
+        Req: Serialize + ?Sized,
+        Resp: Serialize + DeserializeOwned,
+    {
+        match self {
+            Mode::Record(file) => {
+                let result = rpc.await;
+                let exchange = Exchange {
+                    service: service.to_string(),
+                    method: method.to_string(),
+                    request: serde_json::to_value(request)?,
+                    response: match &result {
+                        Ok(response) => Some(serde_json::to_value(response)?),
+                        Err(_) => None,
+                    },
+                    error: result.as_ref().err().map(|e| format!("{:#}", e)),
+                    error_kind: match result
+                        .as_ref()
+                        .err()
+                        .and_then(|e| e.downcast_ref::<Error>())
+                    {
+                        None | Some(Error::Other(_)) => None,
+                        Some(kind) => Some(serde_json::to_value(kind)?),
+                    },
+                };
+                let line = serde_json::to_string(&exchange)?;
+                writeln!(file.lock().unwrap(), "{}", line)?;
+                result
+            }
+            Mode::Replay(recorded) => {
+                let request = serde_json::to_value(request)?;
+                let exchange = {
+                    let mut recorded = recorded.lock().unwrap();
+                    let queue = recorded
+                        .get_mut(&(service.to_string(), method.to_string()))
+                        .filter(|queue| !queue.is_empty())
+                        .with_context(|| {
+                            format!("No recorded {} {} left to replay", service, method)
+                        })?;
+                    let i = queue
+                        .iter()
+                        .position(|e| e.request == request)
+                        .with_context(|| {
+                            format!(
+                                "No recorded {} {} with request {} left to replay",
+                                service, method, request
+                            )
+                        })?;
+                    queue.remove(i).expect("position is in range")
+                };
+                match (exchange.error_kind, exchange.error) {
+                    (Some(kind), _) => Err(serde_json::from_value::<Error>(kind)?.into()),
+                    (None, Some(error)) => Err(anyhow::anyhow!(error)),
+                    (None, None) => Ok(serde_json::from_value(
+                        exchange.response.unwrap_or(Value::Null),
+                    )?),
+                }
+            }
+        }
+    }
+}
+
+/// Turns on recording or replay if `RPC_RECORD` or `RPC_REPLAY` is set.
+pub fn init_from_env() -> Result<()> {
+    let mode = match (std::env::var("RPC_RECORD"), std::env::var("RPC_REPLAY")) {
+        (Ok(_), Ok(_)) => anyhow::bail!("Set either RPC_RECORD or RPC_REPLAY, not both"),
+        (Ok(path), _) => {
+            let mode = Mode::record(&path)?;
+            println!("Recording RPC traffic to {}", path);
+            mode
+        }
+        (_, Ok(path)) => {
+            let mode = Mode::replay(&path)?;
+            println!("Replaying RPC traffic from {}", path);
+            mode
+        }
+        _ => return Ok(()),
+    };
+    MODE.set(mode)
+        .map_err(|_| anyhow::anyhow!("RPC recording is already set up"))
+}
+
+pub fn replaying() -> bool {
+    matches!(MODE.get(), Some(Mode::Replay(_)))
+}
+
+/// Runs `rpc` and records it, or answers it from the recording without
+/// running it. Replay hands out the oldest unused exchange with the same
+/// service, method and request, and fails if there is none: a request that
+/// differs from the recording means the scenario took another path.
+pub async fn call<Req, Resp>(
+    service: &str,
+    method: &str,
+    request: &Req,
+    rpc: impl Future<Output = Result<Resp>>,
+) -> Result<Resp>
+where
+    Req: Serialize + ?Sized,
+    Resp: Serialize + DeserializeOwned,
+{
+    match MODE.get() {
+        None => rpc.await,
+        Some(mode) => mode.call(service, method, request, rpc).await,
+    }
+}
+
+/// A label no earlier run used, `<prefix>-<random hex>`. Recorded like an RPC,
+/// so that a replay sends the same label and finds the recorded requests.
+pub async fn unique_label(prefix: &str) -> Result<String> {
+    call("recorder", "label", prefix, async {
+        Ok(format!("{}-{:016x}", prefix, rand::random::<u64>()))
+    })
+    .await
+}
+
+/// Waits `duration` for the nodes to catch up, or not at all when replaying.
+pub async fn sleep(duration: Duration) {
+    if !replaying() {
+        tokio::time::sleep(duration).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replays_recorded_calls() {
+        let path = std::env::temp_dir().join(format!("recorder-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let record = Mode::record(path).unwrap();
+        let height: u64 = record
+            .call("bitcoind", "getblockcount", &(), async { Ok(101) })
+            .await
+            .unwrap();
+        assert_eq!(height, 101);
+        let timeout = record
+            .call("bitcoind", "getblock", "00", async {
+                Err::<Value, _>(Error::Timeout("waiting for block".into()).into())
+            })
+            .await;
+        assert!(timeout.is_err());
+        drop(record);
+
+        let replay = Mode::replay(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let height: u64 = replay
+            .call("bitcoind", "getblockcount", &(), async {
+                panic!("replay ran the RPC")
+            })
+            .await
+            .unwrap();
+        assert_eq!(height, 101);
+        let err = replay
+            .call::<_, Value>("bitcoind", "getblock", "00", async {
+                panic!("replay ran the RPC")
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::Timeout(_))));
+        // Each exchange is handed out once, and only for the same request
+        assert!(
+            replay
+                .call::<_, u64>("bitcoind", "getblockcount", &(), async { Ok(0) })
+                .await
+                .is_err()
+        );
+    }
+}
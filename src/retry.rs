@@ -0,0 +1,172 @@
+//! Deadlines and retries for Greenlight node calls.
+//!
+//! Every attempt runs under the call's deadline. Calls that are safe to repeat
+//! are retried on transient failures, such as `Unavailable` while the
+//! scheduler is still starting the node, with jittered exponential backoff.
+//! Calls that move funds are attempted once: a `fundchannel` or `pay` that
+//! timed out may still have gone through, and only the caller can check.
+//!
+//! A lost connection is not retried here, since the node may have moved;
+//! `GlNode` asks the scheduler where it runs now and calls again.
+
+use crate::error::{self, Error};
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+
+/// Whether a call can be sent again after it failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Idempotency {
+    /// Reads, and writes that are harmless to repeat, such as `connect`.
+    Safe,
+    /// Calls that must not run twice: those that move funds, where a repeat
+    /// could open a second channel or pay twice, and `stop`.
+    Unsafe,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Limit on each attempt.
+    pub deadline: Duration,
+    /// Attempts in total, the first included. Ignored for unsafe calls.
+    pub max_attempts: u32,
+    /// Backoff before the first retry, doubled for each one after it.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub const SAFE: RetryPolicy = RetryPolicy {
+        deadline: Duration::from_secs(30),
+        max_attempts: 5,
+        initial_backoff: Duration::from_millis(250),
+        max_backoff: Duration::from_secs(5),
+    };
+
+    // Funding waits for the peer to accept the channel, so it gets longer
+    pub const UNSAFE: RetryPolicy = RetryPolicy {
+        deadline: Duration::from_secs(120),
+        max_attempts: 1,
+        initial_backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+    };
+
+    pub fn default_for(idempotency: Idempotency) -> Self {
+        match idempotency {
+            Idempotency::Safe => Self::SAFE,
+            Idempotency::Unsafe => Self::UNSAFE,
+        }
+    }
+
+    /// Backoff before retry number `retry`, counting from 0, with up to half
+    /// of it taken off at random so that callers do not retry in lockstep.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << retry.min(16))
+            .min(self.max_backoff);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Whether the call failed because the connection to the node went away, as
+/// opposed to the node answering with an error.
+pub fn is_connection_lost(error: &Error) -> bool {
+    match error {
+        Error::Grpc { code, message } => {
+            let message = message.to_lowercase();
+            matches!(code, tonic::Code::Unavailable | tonic::Code::Unknown)
+                && ["transport error", "connection", "broken pipe"]
+                    .iter()
+                    .any(|m| message.contains(m))
+        }
+        _ => false,
+    }
+}
+
+/// Whether another attempt of a safe call may succeed where this one failed.
+pub fn is_transient(error: &Error) -> bool {
+    match error {
+        _ if is_connection_lost(error) => false,
+        Error::Timeout(_) | Error::SignerOffline(_) => true,
+        Error::Grpc { code, .. } => matches!(
+            code,
+            tonic::Code::Unavailable
+                | tonic::Code::DeadlineExceeded
+                | tonic::Code::ResourceExhausted
+                | tonic::Code::Aborted
+        ),
+        _ => false,
+    }
+}
+
+/// Runs `attempt` under `policy` and returns its result along with how often
+/// it was retried.
+pub async fn run<T, F, Fut>(
+    policy: RetryPolicy,
+    idempotency: Idempotency,
+    mut attempt: F,
+) -> (error::Result<T>, u32)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = error::Result<T>>,
+{
+    let mut retries = 0;
+    loop {
+        let result = match tokio::time::timeout(policy.deadline, attempt()).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Timeout(format!(
+                "after {:?} waiting for the node",
+                policy.deadline
+            ))),
+        };
+        match result {
+            Err(e)
+                if idempotency == Idempotency::Safe
+                    && retries + 1 < policy.max_attempts
+                    && is_transient(&e) =>
+            {
+                let backoff = policy.backoff(retries);
+                retries += 1;
+                tracing::warn!(
+                    retry = retries,
+                    backoff_ms = backoff.as_millis() as u64,
+                    error = %e,
+                    "Retrying RPC"
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            result => return (result, retries),
+        }
+    }
+}
+
+/// Calls, retries and latency of one method, as kept by `GlNode`.
+#[derive(Clone, Debug, Default)]
+pub struct CallStats {
+    pub calls: u64,
+    pub retries: u64,
+    /// Calls that found the connection lost and had to ask the scheduler again.
+    pub reconnects: u64,
+    pub failures: u64,
+    /// Wall time of all calls, retries and backoff included.
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl CallStats {
+    pub fn record(&mut self, retries: u32, reconnected: bool, latency: Duration, ok: bool) {
+        self.calls += 1;
+        self.retries += retries as u64;
+        self.reconnects += reconnected as u64;
+        self.failures += !ok as u64;
+        self.total_latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+    }
+
+    pub fn mean_latency(&self) -> Duration {
+        self.total_latency
+            .checked_div(self.calls as u32)
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,33 @@
+//! What the idempotent setup steps of both binaries found: state reused from
+//! an earlier run, or created by this one.
+
+/// What each setup step found, printed at the end of a run.
+#[derive(Default)]
+pub struct SetupReport {
+    reused: Vec<String>,
+    created: Vec<String>,
+}
+
+impl SetupReport {
+    pub fn reused(&mut self, step: String) {
+        println!("Reusing {}", step);
+        self.reused.push(step);
+    }
+
+    pub fn created(&mut self, step: String) {
+        println!("Created {}", step);
+        self.created.push(step);
+    }
+
+    pub fn print(&self) {
+        println!("\n=== Setup ===");
+        println!("Reused ({}):", self.reused.len());
+        for step in &self.reused {
+            println!("  {}", step);
+        }
+        println!("Created ({}):", self.created.len());
+        for step in &self.created {
+            println!("  {}", step);
+        }
+    }
+}
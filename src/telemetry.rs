@@ -0,0 +1,105 @@
+//! `tracing` setup shared by both binaries.
+//!
+//! Events go to stderr as pretty text, filtered by `RUST_LOG` (default
+//! `info`, with the signer libraries' chatter at `warn`). `TRACE_JSON=<file>`
+//! also writes them to `<file>` as JSON lines, and
+//! `OTEL_EXPORTER_OTLP_ENDPOINT=<url>` exports spans to an OTLP collector. Both
+//! of those include this crate's debug events, such as every RPC.
+
+use anyhow::Result;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::Resource;
+use std::fs::File;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Flushes the OTLP exporter when dropped at the end of `main`.
+pub struct Guard {
+    otlp: bool,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.otlp {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+    }
+}
+
+fn detail_filter() -> EnvFilter {
+    EnvFilter::new("info,gl_client_tryout=debug,peerswap=debug")
+}
+
+/// Installs the subscriber. `service` names the binary in OTLP traces.
+pub fn init(service: &'static str) -> Result<Guard> {
+    let console = tracing_subscriber::fmt::layer()
+        .pretty()
+        .with_writer(std::io::stderr)
+        .with_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+            EnvFilter::new("info,vls_protocol_signer=warn,lightning_signer=warn")
+        }));
+
+    let json = match std::env::var("TRACE_JSON") {
+        Ok(path) => Some(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(true)
+                .with_writer(Mutex::new(File::create(path)?))
+                .with_filter(detail_filter()),
+        ),
+        Err(_) => None,
+    };
+
+    let otlp = match std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(
+                    opentelemetry_sdk::trace::config()
+                        .with_resource(Resource::new(vec![KeyValue::new("service.name", service)])),
+                )
+                .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+            Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(tracer)
+                    .with_filter(detail_filter()),
+            )
+        }
+        Err(_) => None,
+    };
+
+    let guard = Guard {
+        otlp: otlp.is_some(),
+    };
+    tracing_subscriber::registry()
+        .with(console)
+        .with(json)
+        .with(otlp)
+        .try_init()?;
+    Ok(guard)
+}
+
+/// Awaits `rpc` and logs its outcome and duration in the current span, which
+/// the caller opens with the node and method.
+pub async fn rpc<T>(rpc: impl Future<Output = Result<T>>) -> Result<T> {
+    let started = Instant::now();
+    let result = rpc.await;
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    match &result {
+        Ok(_) => tracing::debug!(elapsed_ms, "RPC succeeded"),
+        Err(e) => tracing::debug!(elapsed_ms, error = %format!("{:#}", e), "RPC failed"),
+    }
+    result
+}